@@ -0,0 +1,212 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::future;
+use futures::prelude::*;
+use u2f_core::{AppId, ApprovalError, Clock, SystemClock, UserPresence, UserVerification};
+
+/// Remembers approved authentications for a short while so the user is not
+/// prompted again for every request from the same application. A remembered
+/// approval is answered with the verification the user originally gave.
+///
+/// Registrations are always passed through to the wrapped check.
+pub struct CachingUserPresence<U> {
+    approvals: Rc<RefCell<HashMap<AppId, (Instant, UserVerification)>>>,
+    clock: Rc<dyn Clock>,
+    inner: U,
+    ttl: Duration,
+}
+
+impl<U: UserPresence> CachingUserPresence<U> {
+    pub fn new(inner: U, ttl: Duration) -> CachingUserPresence<U> {
+        CachingUserPresence {
+            approvals: Rc::new(RefCell::new(HashMap::new())),
+            clock: Rc::new(SystemClock),
+            inner,
+            ttl,
+        }
+    }
+
+    /// Measure the time since each approval with `clock`.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> CachingUserPresence<U> {
+        self.clock = Rc::new(clock);
+        self
+    }
+
+    fn recent_approval(&self, application: &AppId) -> Option<UserVerification> {
+        let mut approvals = self.approvals.borrow_mut();
+        match approvals.get(application) {
+            Some(&(approved_at, verification))
+                if self.clock.now().duration_since(approved_at) < self.ttl =>
+            {
+                Some(verification)
+            }
+            Some(_) => {
+                approvals.remove(application);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+impl<U: UserPresence> UserPresence for CachingUserPresence<U> {
+    fn approve_registration(
+        &self,
+        application: &AppId,
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        self.inner.approve_registration(application)
+    }
+
+    fn approve_authentication(
+        &self,
+        application: &AppId,
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        Box::new(
+            self.approve_authentication_with_verification(application)
+                .map(|verification| verification.is_some()),
+        )
+    }
+
+    fn approve_authentication_with_verification(
+        &self,
+        application: &AppId,
+    ) -> Box<dyn Future<Item = Option<UserVerification>, Error = ApprovalError>> {
+        if let Some(verification) = self.recent_approval(application) {
+            return Box::new(future::ok(Some(verification)));
+        }
+
+        let approvals = self.approvals.clone();
+        let clock = self.clock.clone();
+        let application = *application;
+        Box::new(
+            self.inner
+                .approve_authentication_with_verification(&application)
+                .map(move |verification| {
+                    if let Some(verification) = verification {
+                        approvals
+                            .borrow_mut()
+                            .insert(application, (clock.now(), verification));
+                    }
+                    verification
+                }),
+        )
+    }
+
+    fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
+        self.inner.wink()
+    }
+
+    fn approve_reset(&self) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        self.inner.approve_reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use u2f_core::MockClock;
+
+    use super::*;
+    use crate::fake_user_presence::FakeUserPresence;
+
+    fn caching_user_presence(
+        inner: FakeUserPresence,
+        ttl: Duration,
+        clock: &MockClock,
+    ) -> (CachingUserPresence<FakeUserPresence>, FakeUserPresence) {
+        let user_presence = CachingUserPresence::new(inner.clone(), ttl).with_clock(clock.clone());
+        (user_presence, inner)
+    }
+
+    #[test]
+    fn second_approval_within_ttl_is_not_prompted() {
+        let (user_presence, inner) = caching_user_presence(
+            FakeUserPresence::approving(),
+            Duration::from_secs(60),
+            &MockClock::new(),
+        );
+        let application = AppId::from_bytes(&[0u8; 32]);
+
+        assert!(user_presence
+            .approve_authentication(&application)
+            .wait()
+            .unwrap());
+        assert!(user_presence
+            .approve_authentication(&application)
+            .wait()
+            .unwrap());
+
+        assert_eq!(inner.prompts(), 1);
+    }
+
+    #[test]
+    fn approvals_are_cached_per_application() {
+        let (user_presence, inner) = caching_user_presence(
+            FakeUserPresence::approving(),
+            Duration::from_secs(60),
+            &MockClock::new(),
+        );
+
+        user_presence
+            .approve_authentication(&AppId::from_bytes(&[0u8; 32]))
+            .wait()
+            .unwrap();
+        user_presence
+            .approve_authentication(&AppId::from_bytes(&[1u8; 32]))
+            .wait()
+            .unwrap();
+
+        assert_eq!(inner.prompts(), 2);
+    }
+
+    #[test]
+    fn expired_approval_prompts_again() {
+        let clock = MockClock::new();
+        let (user_presence, inner) = caching_user_presence(
+            FakeUserPresence::approving(),
+            Duration::from_secs(60),
+            &clock,
+        );
+        let application = AppId::from_bytes(&[0u8; 32]);
+
+        user_presence
+            .approve_authentication(&application)
+            .wait()
+            .unwrap();
+        clock.advance(Duration::from_secs(59));
+        user_presence
+            .approve_authentication(&application)
+            .wait()
+            .unwrap();
+        assert_eq!(inner.prompts(), 1);
+
+        clock.advance(Duration::from_secs(1));
+        user_presence
+            .approve_authentication(&application)
+            .wait()
+            .unwrap();
+        assert_eq!(inner.prompts(), 2);
+    }
+
+    #[test]
+    fn cached_approval_keeps_its_verification() {
+        let (user_presence, inner) = caching_user_presence(
+            FakeUserPresence::approving().verifying(),
+            Duration::from_secs(60),
+            &MockClock::new(),
+        );
+        let application = AppId::from_bytes(&[0u8; 32]);
+
+        for _ in 0..2 {
+            let verification = user_presence
+                .approve_authentication_with_verification(&application)
+                .wait()
+                .unwrap();
+            assert_eq!(verification, Some(UserVerification::Verified));
+        }
+        assert_eq!(inner.prompts(), 1);
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io;
 use std::path::Path;
@@ -6,16 +7,71 @@ use std::path::PathBuf;
 use serde_json;
 
 use crate::atomic_file;
+use crate::policy_user_presence::Policy;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub(crate) struct Config {
     pub(crate) secret_store_type: SecretStoreType,
+    /// Skip the prompt for applications approved within this many seconds.
+    /// Off unless set, since meanwhile any local process can get signatures
+    /// for that application without the user noticing.
+    #[serde(default)]
+    pub(crate) approval_cache_seconds: Option<u64>,
+    /// How to answer applications without a policy of their own, prompting
+    /// unless set.
+    #[serde(default)]
+    pub(crate) default_policy: Option<Policy>,
+    /// Policies for individual applications, keyed by app ID, such as
+    /// `"https://github.com"`.
+    #[serde(default)]
+    pub(crate) application_policies: HashMap<String, Policy>,
+    /// Ask for a PIN before every approval, making authentications user
+    /// verified. Off unless set.
+    #[serde(default)]
+    pub(crate) pin: Option<PinConfig>,
 }
 
 #[derive(Serialize, Deserialize, Copy, Clone)]
 pub(crate) enum SecretStoreType {
     File,
+    /// Like `File`, sealed with the passphrase in `SOFTU2F_PASSPHRASE`.
+    EncryptedFile,
     SecretService,
+    #[cfg(feature = "keyring")]
+    Keyring,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct PinConfig {
+    /// Hex encoded SHA-256 of the PIN, so the PIN itself is not on disk.
+    pub(crate) sha256: String,
+    /// Command that asks for the PIN and prints it, such as
+    /// `["zenity", "--password"]`.
+    pub(crate) command: Vec<String>,
+    #[serde(default = "default_pin_retries")]
+    pub(crate) max_retries: u8,
+}
+
+// Same as a CTAP2 authenticator
+fn default_pin_retries() -> u8 {
+    8
+}
+
+impl PinConfig {
+    pub(crate) fn pin_hash(&self) -> io::Result<[u8; 32]> {
+        let invalid =
+            || io::Error::new(io::ErrorKind::InvalidData, "PIN hash must be 64 hex digits");
+        // Checked up front since from_str_radix also accepts a leading sign
+        if self.sha256.len() != 64 || !self.sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let mut hash = [0u8; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte =
+                u8::from_str_radix(&self.sha256[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(hash)
+    }
 }
 
 #[derive(Clone)]
@@ -83,4 +139,61 @@ mod tests {
 
         assert!(ConfigFile::load(file_path).unwrap().is_none());
     }
+
+    #[test]
+    fn load_config_written_before_approval_cache_leaves_it_off() {
+        let temp_dir = TempDir::new("config_tests").unwrap();
+        let file_path = ConfigFilePath::from_dir(temp_dir.path());
+        std::fs::write(file_path.get(), r#"{"secret_store_type":"File"}"#).unwrap();
+
+        let config_file = ConfigFile::load(file_path).unwrap().unwrap();
+
+        let config = config_file.config();
+        assert_eq!(config.approval_cache_seconds, None);
+        assert_eq!(config.default_policy, None);
+        assert!(config.application_policies.is_empty());
+        assert!(config.pin.is_none());
+    }
+
+    #[test]
+    fn load_config_with_policies_and_pin() {
+        let temp_dir = TempDir::new("config_tests").unwrap();
+        let file_path = ConfigFilePath::from_dir(temp_dir.path());
+        std::fs::write(
+            file_path.get(),
+            r#"{
+                "secret_store_type": "EncryptedFile",
+                "default_policy": "AlwaysDeny",
+                "application_policies": {"https://example.com": "Prompt"},
+                "pin": {"sha256": "03ac674216f3e15c761ee1a5e255f067953623c8b388b4459e13f978d7c846f4", "command": ["zenity", "--password"]}
+            }"#,
+        )
+        .unwrap();
+
+        let config_file = ConfigFile::load(file_path).unwrap().unwrap();
+
+        let config = config_file.config();
+        assert_eq!(config.default_policy, Some(Policy::AlwaysDeny));
+        assert_eq!(
+            config.application_policies.get("https://example.com"),
+            Some(&Policy::Prompt)
+        );
+        let pin = config.pin.as_ref().unwrap();
+        assert_eq!(pin.max_retries, 8);
+        assert_eq!(pin.pin_hash().unwrap(), openssl::sha::sha256(b"1234"));
+    }
+
+    #[test]
+    fn malformed_pin_hash_errors() {
+        let pin = PinConfig {
+            sha256: "not a hash".to_owned(),
+            command: vec![],
+            max_retries: 8,
+        };
+
+        assert_eq!(
+            pin.pin_hash().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
 }
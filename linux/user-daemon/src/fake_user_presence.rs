@@ -0,0 +1,93 @@
+use std::cell::Cell;
+use std::io;
+use std::rc::Rc;
+
+use futures::future;
+use futures::prelude::*;
+use u2f_core::{AppId, ApprovalError, UserPresence, UserVerification};
+
+/// Answers every user presence check the same way and counts how often it
+/// was asked. Clones share the count, so a test can keep one to look at
+/// after handing the other to the wrapper under test.
+#[derive(Clone)]
+pub struct FakeUserPresence {
+    answer: Option<bool>,
+    prompts: Rc<Cell<usize>>,
+    verification: UserVerification,
+}
+
+impl FakeUserPresence {
+    pub fn approving() -> FakeUserPresence {
+        FakeUserPresence::answering(Some(true))
+    }
+
+    pub fn denying() -> FakeUserPresence {
+        FakeUserPresence::answering(Some(false))
+    }
+
+    /// Never answers, like a user who walked away from the prompt.
+    pub fn unresponsive() -> FakeUserPresence {
+        FakeUserPresence::answering(None)
+    }
+
+    fn answering(answer: Option<bool>) -> FakeUserPresence {
+        FakeUserPresence {
+            answer,
+            prompts: Rc::new(Cell::new(0)),
+            verification: UserVerification::Unverified,
+        }
+    }
+
+    /// Approved authentications count as user verified.
+    pub fn verifying(mut self) -> FakeUserPresence {
+        self.verification = UserVerification::Verified;
+        self
+    }
+
+    pub fn prompts(&self) -> usize {
+        self.prompts.get()
+    }
+
+    fn prompt(&self) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        self.prompts.set(self.prompts.get() + 1);
+        match self.answer {
+            Some(answer) => Box::new(future::ok(answer)),
+            None => Box::new(future::empty()),
+        }
+    }
+}
+
+impl UserPresence for FakeUserPresence {
+    fn approve_registration(
+        &self,
+        _: &AppId,
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        self.prompt()
+    }
+
+    fn approve_authentication(
+        &self,
+        _: &AppId,
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        self.prompt()
+    }
+
+    fn approve_authentication_with_verification(
+        &self,
+        _: &AppId,
+    ) -> Box<dyn Future<Item = Option<UserVerification>, Error = ApprovalError>> {
+        let verification = self.verification;
+        Box::new(
+            self.prompt()
+                .map(move |user_present| user_present.then_some(verification)),
+        )
+    }
+
+    fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
+        Box::new(future::ok(()))
+    }
+
+    fn approve_reset(&self) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        self.prompt()
+    }
+}
@@ -8,9 +8,12 @@ extern crate dirs;
 extern crate failure;
 extern crate futures;
 extern crate futures_cpupool;
+#[cfg(feature = "keyring")]
+extern crate keyring;
 #[macro_use]
 extern crate lazy_static;
 extern crate notify_rust;
+extern crate openssl;
 #[macro_use]
 extern crate quick_error;
 extern crate secret_service;
@@ -30,6 +33,7 @@ extern crate u2f_core;
 extern crate u2fhid_protocol;
 
 use std::io;
+use std::time::Duration;
 
 use clap::{App, Arg};
 use directories::{ProjectDirs, UserDirs};
@@ -41,20 +45,31 @@ use tokio_core::reactor::{Core, Handle};
 use tokio_io::codec::length_delimited;
 use tokio_serde_bincode::{ReadBincode, WriteBincode};
 use tokio_uds::{UCred, UnixStream};
-use u2f_core::{SecretStore, SecureCryptoOperations, U2F};
+use u2f_core::{AppId, SecureCryptoOperations, UserPresence, U2F};
 use u2fhid_protocol::{Packet, U2FHID};
 
 use softu2f_system_daemon::{
     CreateDeviceError, CreateDeviceRequest, DeviceDescription, SocketInput, SocketOutput,
 };
 
+use crate::caching_user_presence::CachingUserPresence;
+use crate::config::Config;
+use crate::pin_user_presence::{CommandPinEntry, PinUserPresence};
+use crate::policy_user_presence::{Policy, PolicyUserPresence};
 use crate::storage::AppDirs;
+use crate::timeout_user_presence::TimeoutUserPresence;
 use crate::user_presence::NotificationUserPresence;
 
 mod atomic_file;
+mod caching_user_presence;
 mod config;
+#[cfg(test)]
+mod fake_user_presence;
+mod pin_user_presence;
+mod policy_user_presence;
 mod storage;
 mod stores;
+mod timeout_user_presence;
 mod user_presence;
 
 quick_error! {
@@ -116,6 +131,7 @@ const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const PATH_ARG: &str = "path";
+const USER_PRESENCE_TIMEOUT: Duration = Duration::from_secs(30);
 
 fn main() -> Result<(), ProgramError> {
     let args = App::new("SoftU2F System Daemon")
@@ -256,10 +272,22 @@ where
         .filter_map(move |output| socket_output_to_packet(&packet_logger, output))
         .with(|packet| future::ok(packet_to_socket_input(packet)));
 
+    let dirs = match app_dirs() {
+        Ok(dirs) => dirs,
+        Err(err) => return Box::new(future::err(ProgramError::Failure(err.compat()))),
+    };
+    let config = match storage::load_config(&dirs, log) {
+        Ok(config) => config,
+        Err(err) => return Box::new(future::err(ProgramError::Io(err))),
+    };
+
     let attestation = u2f_core::self_signed_attestation();
-    let user_presence = Box::new(NotificationUserPresence::new(&handle, log.new(o!())));
+    let user_presence = match build_user_presence(&config, &handle, log) {
+        Ok(user_presence) => user_presence,
+        Err(err) => return Box::new(future::err(ProgramError::Io(err))),
+    };
     let operations = Box::new(SecureCryptoOperations::new(attestation));
-    let storage = match build_storage(log) {
+    let storage = match storage::build(&dirs, &config, log) {
         Ok(store) => store,
         Err(err) => return Box::new(future::err(ProgramError::Failure(err.compat()))),
     };
@@ -298,19 +326,59 @@ fn packet_to_socket_input(packet: Packet) -> SocketInput {
     ))
 }
 
-fn build_storage(log: &Logger) -> Result<Box<dyn SecretStore>, Error> {
+/// Prompts with a notification, after the PIN if one is configured. Policies
+/// are applied first so a denied application is never cached or prompted for.
+fn build_user_presence(
+    config: &Config,
+    handle: &Handle,
+    log: &Logger,
+) -> io::Result<Box<dyn UserPresence>> {
+    let mut user_presence: Box<dyn UserPresence> =
+        Box::new(NotificationUserPresence::new(handle, log.new(o!())));
+    if let Some(ref pin) = config.pin {
+        let pin_hash = pin.pin_hash()?;
+        info!(log, "Asking for a PIN before every approval"; "max_retries" => pin.max_retries);
+        user_presence = Box::new(PinUserPresence::with_pin_hash(
+            user_presence,
+            CommandPinEntry::new(pin.command.clone()),
+            pin_hash,
+            pin.max_retries,
+        ));
+    }
+    user_presence = Box::new(TimeoutUserPresence::new(
+        user_presence,
+        USER_PRESENCE_TIMEOUT,
+        handle,
+    ));
+    if let Some(seconds) = config.approval_cache_seconds {
+        warn!(log, "Remembering approvals, applications approved recently are not prompted for"; "seconds" => seconds);
+        user_presence = Box::new(CachingUserPresence::new(
+            user_presence,
+            Duration::from_secs(seconds),
+        ));
+    }
+    if config.default_policy.is_some() || !config.application_policies.is_empty() {
+        let default = config.default_policy.unwrap_or(Policy::Prompt);
+        info!(log, "Applying approval policies"; "default" => ?default, "applications" => config.application_policies.len());
+        let policies = config.application_policies.iter().fold(
+            PolicyUserPresence::new(user_presence, default),
+            |policies, (app_id, &policy)| policies.with_policy(AppId::from_app_id(app_id), policy),
+        );
+        user_presence = Box::new(policies);
+    }
+    Ok(user_presence)
+}
+
+fn app_dirs() -> Result<AppDirs, Error> {
     let user_dirs = UserDirs::new().ok_or(HomeDirectoryNotFound)?;
     let project_dirs =
         ProjectDirs::from("com.github", "danstiner", "Rust U2F").ok_or(HomeDirectoryNotFound)?;
 
-    storage::build(
-        &AppDirs {
-            user_home_dir: user_dirs.home_dir().to_owned(),
-            config_dir: project_dirs.config_dir().to_owned(),
-            data_local_dir: project_dirs.data_local_dir().to_owned(),
-        },
-        log,
-    )
+    Ok(AppDirs {
+        user_home_dir: user_dirs.home_dir().to_owned(),
+        config_dir: project_dirs.config_dir().to_owned(),
+        data_local_dir: project_dirs.data_local_dir().to_owned(),
+    })
 }
 
 fn require_root(cred: UCred) -> Result<(), ProgramError> {
@@ -0,0 +1,308 @@
+use std::cell::Cell;
+use std::io;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+
+use futures::future;
+use futures::prelude::*;
+use futures_cpupool::CpuPool;
+use openssl::memcmp;
+use openssl::sha::sha256;
+use u2f_core::{AppId, ApprovalError, UserPresence, UserVerification};
+
+/// Asks the user for their PIN, resolving to `None` if they cancel.
+pub trait PinEntry {
+    fn request_pin(
+        &self,
+        application: &AppId,
+    ) -> Box<dyn Future<Item = Option<String>, Error = ApprovalError>>;
+}
+
+/// Asks for the PIN by running a command, such as `zenity --password`, that
+/// prints it as the first line of its output. The command exiting
+/// unsuccessfully counts as the user cancelling.
+pub struct CommandPinEntry {
+    command: Vec<String>,
+    executor: CpuPool,
+}
+
+impl CommandPinEntry {
+    pub fn new(command: Vec<String>) -> CommandPinEntry {
+        CommandPinEntry {
+            command,
+            executor: CpuPool::new(1),
+        }
+    }
+}
+
+impl PinEntry for CommandPinEntry {
+    fn request_pin(
+        &self,
+        _: &AppId,
+    ) -> Box<dyn Future<Item = Option<String>, Error = ApprovalError>> {
+        let command = self.command.clone();
+        Box::new(self.executor.spawn_fn(move || {
+            let (program, args) = command
+                .split_first()
+                .ok_or_else(|| ApprovalError::Unavailable("no PIN entry command".into()))?;
+            let output = Command::new(program)
+                .args(args)
+                .stdin(Stdio::null())
+                .output()?;
+            if !output.status.success() {
+                return Ok(None);
+            }
+            let stdout = String::from_utf8(output.stdout).map_err(|_| {
+                ApprovalError::Unavailable("PIN entry printed invalid UTF-8".into())
+            })?;
+            Ok(stdout.lines().next().map(str::to_owned))
+        }))
+    }
+}
+
+/// Requires the user to enter their PIN before the wrapped check is asked,
+/// so approved authentications count as user verified.
+///
+/// Like a CTAP2 authenticator, every wrong PIN uses up one of a fixed number
+/// of retries and a correct PIN restores them all. Once the retries run out
+/// the PIN is blocked and every check fails until the daemon is restarted.
+pub struct PinUserPresence<U, P> {
+    entry: P,
+    inner: Rc<U>,
+    max_retries: u8,
+    pin_hash: [u8; 32],
+    retries: Rc<Cell<u8>>,
+}
+
+impl<U: UserPresence + 'static, P: PinEntry> PinUserPresence<U, P> {
+    pub fn new(inner: U, entry: P, pin: &str, max_retries: u8) -> PinUserPresence<U, P> {
+        PinUserPresence::with_pin_hash(inner, entry, sha256(pin.as_bytes()), max_retries)
+    }
+
+    /// Like `new`, for a PIN known only by its SHA-256 hash.
+    pub fn with_pin_hash(
+        inner: U,
+        entry: P,
+        pin_hash: [u8; 32],
+        max_retries: u8,
+    ) -> PinUserPresence<U, P> {
+        PinUserPresence {
+            entry,
+            inner: Rc::new(inner),
+            max_retries,
+            pin_hash,
+            retries: Rc::new(Cell::new(max_retries)),
+        }
+    }
+
+    /// How many more wrong PINs will be accepted before the PIN is blocked.
+    pub fn retries(&self) -> u8 {
+        self.retries.get()
+    }
+
+    fn verify_pin(
+        &self,
+        application: &AppId,
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        if self.retries.get() == 0 {
+            return Box::new(future::err(blocked()));
+        }
+
+        let max_retries = self.max_retries;
+        let pin_hash = self.pin_hash;
+        let retries = self.retries.clone();
+        Box::new(self.entry.request_pin(application).and_then(move |pin| {
+            let pin = match pin {
+                Some(pin) => pin,
+                None => return Ok(false),
+            };
+            // Another prompt may have used up the last retry while this one was open
+            if retries.get() == 0 {
+                return Err(blocked());
+            }
+            // Spend the retry before comparing so it is lost even if the
+            // comparison is somehow interrupted
+            retries.set(retries.get() - 1);
+            if memcmp::eq(&sha256(pin.as_bytes()), &pin_hash) {
+                retries.set(max_retries);
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        }))
+    }
+}
+
+impl<U: UserPresence + 'static, P: PinEntry> UserPresence for PinUserPresence<U, P> {
+    fn approve_registration(
+        &self,
+        application: &AppId,
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        let inner = self.inner.clone();
+        let application = *application;
+        Box::new(self.verify_pin(&application).and_then(
+            move |verified| -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+                if verified {
+                    inner.approve_registration(&application)
+                } else {
+                    Box::new(future::ok(false))
+                }
+            },
+        ))
+    }
+
+    fn approve_authentication(
+        &self,
+        application: &AppId,
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        Box::new(
+            self.approve_authentication_with_verification(application)
+                .map(|verification| verification.is_some()),
+        )
+    }
+
+    fn approve_authentication_with_verification(
+        &self,
+        application: &AppId,
+    ) -> Box<dyn Future<Item = Option<UserVerification>, Error = ApprovalError>> {
+        let inner = self.inner.clone();
+        let application = *application;
+        Box::new(self.verify_pin(&application).and_then(
+            move |verified| -> Box<dyn Future<Item = _, Error = ApprovalError>> {
+                if verified {
+                    Box::new(
+                        inner
+                            .approve_authentication(&application)
+                            .map(|user_present| user_present.then_some(UserVerification::Verified)),
+                    )
+                } else {
+                    Box::new(future::ok(None))
+                }
+            },
+        ))
+    }
+
+    fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
+        self.inner.wink()
+    }
+
+    // Like a FIDO2 reset, no PIN needed, it is how a forgotten PIN is recovered
+    fn approve_reset(&self) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        self.inner.approve_reset()
+    }
+}
+
+fn blocked() -> ApprovalError {
+    ApprovalError::Unavailable("PIN is blocked after too many wrong attempts".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::fake_user_presence::FakeUserPresence;
+
+    /// Answers each PIN request with the next of the given PINs.
+    struct ScriptedPinEntry {
+        pins: Rc<RefCell<Vec<&'static str>>>,
+    }
+
+    impl PinEntry for ScriptedPinEntry {
+        fn request_pin(
+            &self,
+            _: &AppId,
+        ) -> Box<dyn Future<Item = Option<String>, Error = ApprovalError>> {
+            let pin = self.pins.borrow_mut().remove(0);
+            Box::new(future::ok(Some(pin.to_owned())))
+        }
+    }
+
+    fn pin_user_presence(
+        pins: Vec<&'static str>,
+    ) -> PinUserPresence<FakeUserPresence, ScriptedPinEntry> {
+        let entry = ScriptedPinEntry {
+            pins: Rc::new(RefCell::new(pins)),
+        };
+        PinUserPresence::new(FakeUserPresence::approving(), entry, "1234", 3)
+    }
+
+    fn application() -> AppId {
+        AppId::from_bytes(&[0u8; 32])
+    }
+
+    #[test]
+    fn correct_pin_approves_as_verified() {
+        let user_presence = pin_user_presence(vec!["1234"]);
+
+        let verification = user_presence
+            .approve_authentication_with_verification(&application())
+            .wait()
+            .unwrap();
+
+        assert_eq!(verification, Some(UserVerification::Verified));
+    }
+
+    #[test]
+    fn wrong_pin_is_denied_and_uses_a_retry() {
+        let user_presence = pin_user_presence(vec!["4321"]);
+
+        let user_present = user_presence
+            .approve_registration(&application())
+            .wait()
+            .unwrap();
+
+        assert!(!user_present);
+        assert_eq!(user_presence.retries(), 2);
+    }
+
+    #[test]
+    fn correct_pin_restores_retries() {
+        let user_presence = pin_user_presence(vec!["0000", "0000", "1234"]);
+
+        for _ in 0..3 {
+            user_presence
+                .approve_authentication(&application())
+                .wait()
+                .unwrap();
+        }
+
+        assert_eq!(user_presence.retries(), 3);
+    }
+
+    #[test]
+    fn pin_is_blocked_once_retries_are_used_up() {
+        let user_presence = pin_user_presence(vec!["0000", "0000", "0000", "1234"]);
+
+        for _ in 0..3 {
+            assert!(!user_presence
+                .approve_authentication(&application())
+                .wait()
+                .unwrap());
+        }
+
+        assert_eq!(user_presence.retries(), 0);
+        assert!(user_presence
+            .approve_authentication(&application())
+            .wait()
+            .is_err());
+    }
+
+    #[test]
+    fn command_pin_entry_reads_first_line() {
+        let entry = CommandPinEntry::new(vec!["printf".into(), "1234\\nignored".into()]);
+
+        let pin = entry.request_pin(&application()).wait().unwrap();
+
+        assert_eq!(pin, Some("1234".to_owned()));
+    }
+
+    #[test]
+    fn command_pin_entry_failing_is_cancelled() {
+        let entry = CommandPinEntry::new(vec!["false".into()]);
+
+        let pin = entry.request_pin(&application()).wait().unwrap();
+
+        assert_eq!(pin, None);
+    }
+}
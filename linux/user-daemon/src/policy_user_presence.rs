@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::io;
+
+use futures::future;
+use futures::prelude::*;
+use u2f_core::{AppId, ApprovalError, UserPresence, UserVerification};
+
+/// How to answer user presence checks for an application.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Policy {
+    AlwaysAllow,
+    AlwaysDeny,
+    Prompt,
+}
+
+/// Answers user presence checks from per-application policies, only asking
+/// the wrapped check for applications whose policy is `Prompt`.
+///
+/// Applications without a policy of their own use the default policy.
+pub struct PolicyUserPresence<U> {
+    default: Policy,
+    inner: U,
+    policies: HashMap<AppId, Policy>,
+}
+
+impl<U: UserPresence> PolicyUserPresence<U> {
+    pub fn new(inner: U, default: Policy) -> PolicyUserPresence<U> {
+        PolicyUserPresence {
+            default,
+            inner,
+            policies: HashMap::new(),
+        }
+    }
+
+    pub fn with_policy(mut self, application: AppId, policy: Policy) -> PolicyUserPresence<U> {
+        self.policies.insert(application, policy);
+        self
+    }
+
+    fn policy(&self, application: &AppId) -> Policy {
+        self.policies
+            .get(application)
+            .cloned()
+            .unwrap_or(self.default)
+    }
+
+    fn apply(
+        &self,
+        application: &AppId,
+        prompt: impl FnOnce() -> Box<dyn Future<Item = bool, Error = ApprovalError>>,
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        match self.policy(application) {
+            Policy::AlwaysAllow => Box::new(future::ok(true)),
+            Policy::AlwaysDeny => Box::new(future::ok(false)),
+            Policy::Prompt => prompt(),
+        }
+    }
+}
+
+impl<U: UserPresence> UserPresence for PolicyUserPresence<U> {
+    fn approve_registration(
+        &self,
+        application: &AppId,
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        self.apply(application, || self.inner.approve_registration(application))
+    }
+
+    fn approve_authentication(
+        &self,
+        application: &AppId,
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        self.apply(application, || {
+            self.inner.approve_authentication(application)
+        })
+    }
+
+    // An allowed application was never asked, so the user is not verified
+    fn approve_authentication_with_verification(
+        &self,
+        application: &AppId,
+    ) -> Box<dyn Future<Item = Option<UserVerification>, Error = ApprovalError>> {
+        match self.policy(application) {
+            Policy::AlwaysAllow => Box::new(future::ok(Some(UserVerification::Unverified))),
+            Policy::AlwaysDeny => Box::new(future::ok(None)),
+            Policy::Prompt => self
+                .inner
+                .approve_authentication_with_verification(application),
+        }
+    }
+
+    fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
+        self.inner.wink()
+    }
+
+    // Policies are per application, a reset is not for any one of them
+    fn approve_reset(&self) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        self.inner.approve_reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_user_presence::FakeUserPresence;
+
+    fn policy_user_presence(
+        inner: FakeUserPresence,
+        default: Policy,
+    ) -> (PolicyUserPresence<FakeUserPresence>, FakeUserPresence) {
+        (PolicyUserPresence::new(inner.clone(), default), inner)
+    }
+
+    #[test]
+    fn always_allow_approves_without_prompting() {
+        let application = AppId::from_bytes(&[0u8; 32]);
+        let (user_presence, inner) =
+            policy_user_presence(FakeUserPresence::denying(), Policy::Prompt);
+        let user_presence = user_presence.with_policy(application, Policy::AlwaysAllow);
+
+        assert!(user_presence
+            .approve_authentication(&application)
+            .wait()
+            .unwrap());
+        assert!(user_presence
+            .approve_registration(&application)
+            .wait()
+            .unwrap());
+        assert_eq!(inner.prompts(), 0);
+    }
+
+    #[test]
+    fn always_deny_denies_without_prompting() {
+        let application = AppId::from_bytes(&[0u8; 32]);
+        let (user_presence, inner) =
+            policy_user_presence(FakeUserPresence::approving(), Policy::Prompt);
+        let user_presence = user_presence.with_policy(application, Policy::AlwaysDeny);
+
+        assert!(!user_presence
+            .approve_authentication(&application)
+            .wait()
+            .unwrap());
+        assert!(!user_presence
+            .approve_registration(&application)
+            .wait()
+            .unwrap());
+        assert_eq!(inner.prompts(), 0);
+    }
+
+    #[test]
+    fn prompt_asks_inner_user_presence() {
+        let application = AppId::from_bytes(&[0u8; 32]);
+        let (user_presence, inner) =
+            policy_user_presence(FakeUserPresence::approving(), Policy::AlwaysDeny);
+        let user_presence = user_presence.with_policy(application, Policy::Prompt);
+
+        assert!(user_presence
+            .approve_authentication(&application)
+            .wait()
+            .unwrap());
+        assert_eq!(inner.prompts(), 1);
+    }
+
+    #[test]
+    fn application_without_policy_uses_default() {
+        let (user_presence, inner) =
+            policy_user_presence(FakeUserPresence::denying(), Policy::AlwaysAllow);
+        let user_presence =
+            user_presence.with_policy(AppId::from_bytes(&[1u8; 32]), Policy::AlwaysDeny);
+
+        assert!(user_presence
+            .approve_authentication(&AppId::from_bytes(&[0u8; 32]))
+            .wait()
+            .unwrap());
+        assert_eq!(inner.prompts(), 0);
+    }
+
+    #[test]
+    fn prompt_passes_verification_through() {
+        let application = AppId::from_bytes(&[0u8; 32]);
+        let (user_presence, _) =
+            policy_user_presence(FakeUserPresence::approving().verifying(), Policy::Prompt);
+
+        let verification = user_presence
+            .approve_authentication_with_verification(&application)
+            .wait()
+            .unwrap();
+
+        assert_eq!(verification, Some(UserVerification::Verified));
+    }
+
+    #[test]
+    fn always_allow_approves_verification_as_unverified() {
+        let application = AppId::from_bytes(&[0u8; 32]);
+        let (user_presence, inner) = policy_user_presence(
+            FakeUserPresence::approving().verifying(),
+            Policy::AlwaysAllow,
+        );
+
+        let verification = user_presence
+            .approve_authentication_with_verification(&application)
+            .wait()
+            .unwrap();
+
+        assert_eq!(verification, Some(UserVerification::Unverified));
+        assert_eq!(inner.prompts(), 0);
+    }
+}
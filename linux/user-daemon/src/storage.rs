@@ -1,4 +1,6 @@
 use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::env;
 use std::io;
 use std::path::PathBuf;
 
@@ -6,25 +8,39 @@ use slog::Logger;
 use u2f_core::SecretStore;
 
 use crate::config::{Config, ConfigFile, ConfigFilePath, SecretStoreType};
+use crate::stores::encrypted_file_store::EncryptedFileStore;
 use crate::stores::file_store::FileStore;
 use crate::stores::file_store_v2::FileStoreV2;
+#[cfg(feature = "keyring")]
+use crate::stores::keyring_store::KeyringStore;
 use crate::stores::secret_service_store::SecretServiceStore;
 use crate::stores::UserSecretStore;
 
+const PASSPHRASE_VAR: &str = "SOFTU2F_PASSPHRASE";
+
+#[derive(Debug, Fail)]
+#[fail(display = "the encrypted secret store needs its passphrase in SOFTU2F_PASSPHRASE")]
+struct PassphraseNotSet;
+
 pub struct AppDirs {
     pub user_home_dir: PathBuf,
     pub config_dir: PathBuf,
     pub data_local_dir: PathBuf,
 }
 
-pub(crate) fn build(dirs: &AppDirs, log: &Logger) -> Result<Box<dyn SecretStore>, failure::Error> {
-    let config = determine_config(dirs, log)?;
-    let secret_store = build_secret_store(dirs, &config, log)?;
+pub(crate) fn build(
+    dirs: &AppDirs,
+    config: &Config,
+    log: &Logger,
+) -> Result<Box<dyn SecretStore>, failure::Error> {
+    let secret_store = build_secret_store(dirs, config, log)?;
     migrate_legacy_file_store(dirs, secret_store.borrow(), log)?;
     Ok(secret_store.into_u2f_store())
 }
 
-fn determine_config(dirs: &AppDirs, log: &Logger) -> io::Result<Config> {
+/// Loads the configuration file, creating it with defaults suited to this
+/// system if there is none yet.
+pub(crate) fn load_config(dirs: &AppDirs, log: &Logger) -> io::Result<Config> {
     let config_file_path = ConfigFilePath::from_dir(&dirs.config_dir);
     let config_file = match ConfigFile::load(config_file_path.clone())? {
         Some(config) => {
@@ -38,7 +54,13 @@ fn determine_config(dirs: &AppDirs, log: &Logger) -> io::Result<Config> {
             } else {
                 secret_store_type = SecretStoreType::File;
             }
-            let config = Config { secret_store_type };
+            let config = Config {
+                secret_store_type,
+                approval_cache_seconds: None,
+                default_policy: None,
+                application_policies: HashMap::new(),
+                pin: None,
+            };
             info!(log, "Creating configuration file"; "path" => config_file_path.get().display());
             ConfigFile::create(config_file_path, config)?
         }
@@ -59,11 +81,22 @@ fn build_secret_store(
             );
             Ok(Box::new(SecretServiceStore::new()?))
         }
+        #[cfg(feature = "keyring")]
+        SecretStoreType::Keyring => {
+            info!(log, "Storing secrets in your platform keyring");
+            Ok(Box::new(KeyringStore::new()))
+        }
         SecretStoreType::File => {
             let store_dir = dirs.data_local_dir.as_path();
             warn!(log, "Storing secrets in an unencrypted file"; "dir" => store_dir.display());
             Ok(Box::new(FileStoreV2::new(store_dir)?))
         }
+        SecretStoreType::EncryptedFile => {
+            let store_dir = dirs.data_local_dir.as_path();
+            let passphrase = env::var(PASSPHRASE_VAR).map_err(|_| PassphraseNotSet)?;
+            info!(log, "Storing secrets in a passphrase encrypted file"; "dir" => store_dir.display());
+            Ok(Box::new(EncryptedFileStore::open(store_dir, &passphrase)?))
+        }
     }
 }
 
@@ -46,6 +46,8 @@ impl FileStore {
                 Secret {
                     application_key: application_key.clone(),
                     counter: *counter,
+                    created_at: None,
+                    last_used_at: None,
                 }
             })
             .collect();
@@ -1,19 +1,43 @@
-use std::fs::File;
+use std::cell::RefCell;
+use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use serde_json;
-use u2f_core::{AppId, ApplicationKey, Counter, KeyHandle, SecretStore};
+use u2f_core::{AppId, ApplicationKey, Counter, CredentialInfo, KeyHandle, SecretStore};
 
 use crate::atomic_file;
 use crate::stores::{Secret, UserSecretStore};
 
-#[derive(Serialize, Deserialize)]
-struct Data {
+/// Layout version written with every `Data`. Bump it and teach `migrate`
+/// to upgrade the previous layout whenever `Data` changes shape.
+pub(crate) const STORE_FORMAT_VERSION: u64 = 1;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Data {
+    version: u64,
     secrets: Vec<Secret>,
 }
 
 impl Data {
+    pub(crate) fn new() -> Data {
+        Data {
+            version: STORE_FORMAT_VERSION,
+            secrets: Vec::new(),
+        }
+    }
+    pub(crate) fn from_slice(bytes: &[u8]) -> io::Result<Data> {
+        let value: serde_json::Value = serde_json::from_slice(bytes)?;
+        // Files written before the version was recorded use the first layout
+        let version = match value.get("version") {
+            Some(version) => version.as_u64().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid store format version")
+            })?,
+            None => 1,
+        };
+        migrate(version, value)
+    }
     fn find_secret(&self, application: &AppId, handle: &KeyHandle) -> Option<&Secret> {
         self.secrets.iter().find(|s| {
             s.application_key.application.eq_consttime(application)
@@ -26,32 +50,136 @@ impl Data {
                 && s.application_key.handle.eq_consttime(handle)
         })
     }
-    fn push(&mut self, secret: Secret) {
+    pub(crate) fn push(&mut self, secret: Secret) {
         self.secrets.push(secret)
     }
+    pub(crate) fn remove(&mut self, application: &AppId, handle: &KeyHandle) -> bool {
+        let len_before = self.secrets.len();
+        self.secrets.retain(|s| {
+            !(s.application_key.application.eq_consttime(application)
+                && s.application_key.handle.eq_consttime(handle))
+        });
+        self.secrets.len() < len_before
+    }
+    pub(crate) fn keys(&self) -> Vec<(AppId, KeyHandle)> {
+        self.secrets
+            .iter()
+            .map(|s| {
+                (
+                    s.application_key.application,
+                    s.application_key.handle.clone(),
+                )
+            })
+            .collect()
+    }
+    pub(crate) fn credentials(&self) -> Vec<CredentialInfo> {
+        self.secrets
+            .iter()
+            .map(|s| CredentialInfo {
+                application: s.application_key.application,
+                handle: s.application_key.handle.clone(),
+                created_at: s.created_at,
+                last_used_at: s.last_used_at,
+            })
+            .collect()
+    }
+    pub(crate) fn counter(&self, application: &AppId, handle: &KeyHandle) -> io::Result<Counter> {
+        self.find_secret(application, handle)
+            .map(|secret| secret.counter)
+            .ok_or_else(not_found)
+    }
+    pub(crate) fn increment_counter(
+        &mut self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> io::Result<Counter> {
+        let secret = self
+            .find_secret_mut(application, handle)
+            .ok_or_else(not_found)?;
+        let new_counter = secret
+            .counter
+            .checked_add(1)
+            .ok_or(io::Error::new(io::ErrorKind::Other, "counter exhausted"))?;
+        secret.counter = new_counter;
+        secret.last_used_at = Some(SystemTime::now());
+        Ok(new_counter)
+    }
+    pub(crate) fn application_key(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> Option<ApplicationKey> {
+        self.find_secret(application, handle)
+            .map(|secret| secret.application_key.clone())
+    }
 }
 
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "no such credential")
+}
+
+/// Upgrades a store written in an older layout to the current one.
+fn migrate(version: u64, mut value: serde_json::Value) -> io::Result<Data> {
+    match version {
+        1 => {
+            value["version"] = serde_json::Value::from(STORE_FORMAT_VERSION);
+            Ok(serde_json::from_value(value)?)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported store format version {}", version),
+        )),
+    }
+}
+
+/// Keeps secrets in a JSON file, rewritten atomically on every change.
+///
+/// Between `begin` and `commit` changes are staged in memory instead, and
+/// the file is only rewritten once they are committed.
 pub struct FileStoreV2 {
     path: PathBuf,
+    staged: RefCell<Option<Data>>,
 }
 
 impl FileStoreV2 {
     pub fn new(dir: &Path) -> io::Result<FileStoreV2> {
-        let path = dir.to_owned().join("secrets.json");
-        Ok(FileStoreV2 { path })
+        Ok(FileStoreV2::with_path(dir.to_owned().join("secrets.json")))
+    }
+
+    fn with_path(path: PathBuf) -> FileStoreV2 {
+        FileStoreV2 {
+            path,
+            staged: RefCell::new(None),
+        }
     }
 
     fn read(&self) -> io::Result<Data> {
-        match File::open(&self.path) {
-            Ok(file) => serde_json::from_reader(file).map_err(|e| e.into()),
-            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Data {
-                secrets: Vec::new(),
-            }),
-            Err(err) => Err(err),
+        match *self.staged.borrow() {
+            Some(ref data) => Ok(data.clone()),
+            None => self.read_file(),
         }
     }
 
     fn write(&self, data: &Data) -> io::Result<()> {
+        let mut staged = self.staged.borrow_mut();
+        match *staged {
+            Some(_) => {
+                *staged = Some(data.clone());
+                Ok(())
+            }
+            None => self.write_file(data),
+        }
+    }
+
+    fn read_file(&self) -> io::Result<Data> {
+        match fs::read(&self.path) {
+            Ok(bytes) => Data::from_slice(&bytes),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(Data::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn write_file(&self, data: &Data) -> io::Result<()> {
         atomic_file::overwrite(&self.path, move |writer| {
             serde_json::to_writer_pretty(writer, &data).map_err(|e| e.into())
         })
@@ -73,24 +201,21 @@ impl UserSecretStore for FileStoreV2 {
 impl SecretStore for FileStoreV2 {
     fn add_application_key(&self, key: &ApplicationKey) -> io::Result<()> {
         let mut data = self.read()?;
-        data.push(Secret {
-            application_key: key.clone(),
-            counter: 0,
-        });
+        data.push(Secret::new(key.clone()));
         self.write(&data)
     }
 
+    fn retrieve_counter(&self, application: &AppId, handle: &KeyHandle) -> io::Result<Counter> {
+        self.read()?.counter(application, handle)
+    }
+
     fn get_and_increment_counter(
         &self,
         application: &AppId,
         handle: &KeyHandle,
     ) -> io::Result<Counter> {
         let mut data = self.read()?;
-        let secret = data
-            .find_secret_mut(application, handle)
-            .ok_or(io::Error::new(io::ErrorKind::Other, ""))?;
-        let new_counter = secret.counter + 1;
-        secret.counter = new_counter;
+        let new_counter = data.increment_counter(application, handle)?;
         self.write(&data)?;
         Ok(new_counter)
     }
@@ -100,10 +225,45 @@ impl SecretStore for FileStoreV2 {
         application: &AppId,
         handle: &KeyHandle,
     ) -> io::Result<Option<ApplicationKey>> {
-        Ok(self
-            .read()?
-            .find_secret(application, handle)
-            .map(|secret| secret.application_key.clone()))
+        Ok(self.read()?.application_key(application, handle))
+    }
+
+    fn delete_application_key(&self, application: &AppId, handle: &KeyHandle) -> io::Result<bool> {
+        let mut data = self.read()?;
+        if !data.remove(application, handle) {
+            return Ok(false);
+        }
+        self.write(&data)?;
+        Ok(true)
+    }
+
+    fn list_application_keys(&self) -> io::Result<Vec<(AppId, KeyHandle)>> {
+        Ok(self.read()?.keys())
+    }
+
+    fn list_credentials(&self) -> io::Result<Vec<CredentialInfo>> {
+        Ok(self.read()?.credentials())
+    }
+
+    fn begin(&self) -> io::Result<()> {
+        if self.staged.borrow().is_some() {
+            return Err(io::Error::other("transaction already in progress"));
+        }
+        let data = self.read_file()?;
+        *self.staged.borrow_mut() = Some(data);
+        Ok(())
+    }
+
+    fn commit(&self) -> io::Result<()> {
+        match self.staged.borrow_mut().take() {
+            Some(data) => self.write_file(&data),
+            None => Ok(()),
+        }
+    }
+
+    fn rollback(&self) -> io::Result<()> {
+        self.staged.borrow_mut().take();
+        Ok(())
     }
 }
 
@@ -111,7 +271,7 @@ impl SecretStore for FileStoreV2 {
 mod tests {
     extern crate tempdir;
 
-    use u2f_core::PrivateKey;
+    use u2f_core::{InMemoryStore, PrivateKey};
 
     use super::*;
 
@@ -135,11 +295,23 @@ i2L2wGDHkWWIJJSthmgwkZovXHyMXMpDhw==
         KeyHandle::from(&Vec::new())
     }
 
+    #[test]
+    fn retrieve_counter_for_unknown_key_is_not_found() {
+        let dir = TempDir::new("file_store_tests").unwrap();
+        let store = FileStoreV2::with_path(dir.path().join("store"));
+
+        let err = store
+            .retrieve_counter(&fake_app_id(), &fake_key_handle())
+            .unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
     #[test]
     fn get_and_increment_counter() {
         let dir = TempDir::new("file_store_tests").unwrap();
         let path = dir.path().join("store");
-        let store = FileStoreV2 { path };
+        let store = FileStoreV2::with_path(path);
         let app_id = fake_app_id();
         let handle = fake_key_handle();
         let key = fake_key();
@@ -160,7 +332,7 @@ i2L2wGDHkWWIJJSthmgwkZovXHyMXMpDhw==
     fn retrieve_application_key() {
         let dir = TempDir::new("file_store_tests").unwrap();
         let path = dir.path().join("store");
-        let store = FileStoreV2 { path };
+        let store = FileStoreV2::with_path(path);
         let app_id = fake_app_id();
         let handle = fake_key_handle();
         let key = fake_key();
@@ -177,11 +349,176 @@ i2L2wGDHkWWIJJSthmgwkZovXHyMXMpDhw==
         // Skip key field, it is not easily comparable
     }
 
+    #[test]
+    fn delete_application_key() {
+        let dir = TempDir::new("file_store_tests").unwrap();
+        let path = dir.path().join("store");
+        let store = FileStoreV2::with_path(path);
+        let app_id = fake_app_id();
+        let handle = fake_key_handle();
+        let key = fake_key();
+        let app_key = ApplicationKey::new(app_id, handle, key);
+        store.add_application_key(&app_key).unwrap();
+
+        let deleted = store
+            .delete_application_key(&app_key.application, &app_key.handle)
+            .unwrap();
+
+        assert!(deleted);
+        assert!(store
+            .retrieve_application_key(&app_key.application, &app_key.handle)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn reopened_store_retains_keys_and_counters() {
+        let dir = TempDir::new("file_store_tests").unwrap();
+        let app_id = fake_app_id();
+        let handle = fake_key_handle();
+        let key = fake_key();
+        let app_key = ApplicationKey::new(app_id, handle, key);
+        let counter0 = {
+            let store = FileStoreV2::new(dir.path()).unwrap();
+            store.add_application_key(&app_key).unwrap();
+            store
+                .get_and_increment_counter(&app_id, &app_key.handle)
+                .unwrap()
+        };
+
+        let store = FileStoreV2::new(dir.path()).unwrap();
+        let retrieved_app_key = store
+            .retrieve_application_key(&app_key.application, &app_key.handle)
+            .unwrap()
+            .unwrap();
+        let counter1 = store
+            .get_and_increment_counter(&app_id, &app_key.handle)
+            .unwrap();
+
+        assert_eq!(retrieved_app_key.handle, app_key.handle);
+        assert_eq!(counter0 + 1, counter1);
+    }
+
+    #[test]
+    fn authentication_updates_last_used_but_not_created() {
+        let dir = TempDir::new("file_store_tests").unwrap();
+        let app_id = fake_app_id();
+        let app_key = ApplicationKey::new(app_id, fake_key_handle(), fake_key());
+        let added = {
+            let store = FileStoreV2::new(dir.path()).unwrap();
+            store.add_application_key(&app_key).unwrap();
+            store.list_credentials().unwrap().remove(0)
+        };
+
+        let store = FileStoreV2::new(dir.path()).unwrap();
+        store
+            .get_and_increment_counter(&app_id, &app_key.handle)
+            .unwrap();
+        let used = FileStoreV2::new(dir.path())
+            .unwrap()
+            .list_credentials()
+            .unwrap()
+            .remove(0);
+
+        assert!(added.created_at.is_some());
+        assert_eq!(added.last_used_at, None);
+        assert_eq!(used.created_at, added.created_at);
+        assert!(used.last_used_at >= added.created_at);
+    }
+
+    #[test]
+    fn import_restores_exported_keys_and_counters() {
+        let dir = TempDir::new("file_store_tests").unwrap();
+        let path = dir.path().join("store");
+        let store = FileStoreV2::with_path(path);
+        let app_id = fake_app_id();
+        let app_key = ApplicationKey::new(app_id, fake_key_handle(), fake_key());
+        let exporting_store = InMemoryStore::new();
+        exporting_store.add_application_key(&app_key).unwrap();
+        exporting_store
+            .get_and_increment_counter(&app_id, &app_key.handle)
+            .unwrap();
+
+        store.import(&exporting_store.export().unwrap()).unwrap();
+
+        assert_eq!(
+            store.list_application_keys().unwrap(),
+            vec![(app_id, app_key.handle.clone())]
+        );
+        assert_eq!(store.retrieve_counter(&app_id, &app_key.handle).unwrap(), 1);
+    }
+
+    #[test]
+    fn rollback_leaves_file_unchanged() {
+        let dir = TempDir::new("file_store_tests").unwrap();
+        let path = dir.path().join("store");
+        let store = FileStoreV2::with_path(path.clone());
+        let app_id = fake_app_id();
+        let app_key = ApplicationKey::new(app_id, fake_key_handle(), fake_key());
+        store.add_application_key(&app_key).unwrap();
+        let before = fs::read(&path).unwrap();
+
+        store.begin().unwrap();
+        store
+            .get_and_increment_counter(&app_id, &app_key.handle)
+            .unwrap();
+        store
+            .delete_application_key(&app_id, &app_key.handle)
+            .unwrap();
+        store.rollback().unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), before);
+        assert_eq!(store.retrieve_counter(&app_id, &app_key.handle).unwrap(), 0);
+    }
+
+    #[test]
+    fn staged_changes_are_written_on_commit() {
+        let dir = TempDir::new("file_store_tests").unwrap();
+        let path = dir.path().join("store");
+        let store = FileStoreV2::with_path(path.clone());
+        let app_id = fake_app_id();
+        let app_key = ApplicationKey::new(app_id, fake_key_handle(), fake_key());
+
+        store.begin().unwrap();
+        store.add_application_key(&app_key).unwrap();
+        assert_eq!(store.list_application_keys().unwrap().len(), 1);
+        assert!(!path.exists());
+        store.commit().unwrap();
+
+        let reopened = FileStoreV2::with_path(path);
+        assert_eq!(
+            reopened.list_application_keys().unwrap(),
+            vec![(app_id, app_key.handle.clone())]
+        );
+    }
+
+    #[test]
+    fn read_store_without_version_succeeds() {
+        let dir = TempDir::new("file_store_tests").unwrap();
+        let path = dir.path().join("store");
+        fs::write(&path, r#"{"secrets":[]}"#).unwrap();
+        let store = FileStoreV2::with_path(path);
+
+        assert!(store.list_application_keys().unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_store_from_future_version_errors() {
+        let dir = TempDir::new("file_store_tests").unwrap();
+        let path = dir.path().join("store");
+        fs::write(&path, r#"{"version":2,"secrets":[{"unknown":"layout"}]}"#).unwrap();
+        let store = FileStoreV2::with_path(path);
+
+        let error = store.list_application_keys().unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
     #[test]
     fn retrieve_nonexistent_key_is_none() {
         let dir = TempDir::new("file_store_tests").unwrap();
         let path = dir.path().join("store");
-        let store = FileStoreV2 { path };
+        let store = FileStoreV2::with_path(path);
 
         let key = store
             .retrieve_application_key(&fake_app_id(), &fake_key_handle())
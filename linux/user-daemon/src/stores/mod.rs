@@ -1,18 +1,51 @@
 use std::io;
+use std::time::SystemTime;
 
-use u2f_core::{ApplicationKey, Counter, SecretStore};
+use u2f_core::{read_backup, ApplicationKey, Counter, SecretStore};
 
+pub(crate) mod encrypted_file_store;
 pub(crate) mod file_store;
 pub(crate) mod file_store_v2;
+#[cfg(feature = "keyring")]
+pub(crate) mod keyring_store;
 pub(crate) mod secret_service_store;
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Secret {
     application_key: ApplicationKey,
     counter: Counter,
+    // Missing from secrets stored before they were tracked
+    #[serde(default)]
+    created_at: Option<SystemTime>,
+    #[serde(default)]
+    last_used_at: Option<SystemTime>,
+}
+
+impl Secret {
+    /// A newly registered secret.
+    pub(crate) fn new(application_key: ApplicationKey) -> Secret {
+        Secret {
+            application_key,
+            counter: 0,
+            created_at: Some(SystemTime::now()),
+            last_used_at: None,
+        }
+    }
 }
 
 pub trait UserSecretStore: SecretStore {
     fn add_secret(&self, secret: Secret) -> io::Result<()>;
+    /// Adds every credential from a backup made by `SecretStore::export`.
+    fn import(&self, backup: &[u8]) -> io::Result<()> {
+        for (application_key, counter) in read_backup(backup)? {
+            self.add_secret(Secret {
+                application_key,
+                counter,
+                created_at: None,
+                last_used_at: None,
+            })?;
+        }
+        Ok(())
+    }
     fn into_u2f_store(self: Box<Self>) -> Box<dyn SecretStore>;
 }
@@ -77,11 +77,8 @@ impl UserSecretStore for SecretServiceStore {
                 secret.application_key.application.to_base64()
             ),
         };
-        let secret = serde_json::to_string(&Secret {
-            application_key: secret.application_key.clone(),
-            counter: secret.counter,
-        })
-        .map_err(|error| io::Error::new(ErrorKind::Other, error))?;
+        let secret = serde_json::to_string(&secret)
+            .map_err(|error| io::Error::new(ErrorKind::Other, error))?;
         let content_type = "application/json";
         let _item = collection
             .create_item(&label, attributes, secret.as_bytes(), false, content_type)
@@ -96,10 +93,23 @@ impl UserSecretStore for SecretServiceStore {
 
 impl SecretStore for SecretServiceStore {
     fn add_application_key(&self, key: &ApplicationKey) -> io::Result<()> {
-        self.add_secret(Secret {
-            application_key: key.clone(),
-            counter: 0,
-        })
+        self.add_secret(Secret::new(key.clone()))
+    }
+
+    fn retrieve_counter(&self, application: &AppId, handle: &KeyHandle) -> io::Result<Counter> {
+        let collection = self
+            .service
+            .get_default_collection()
+            .map_err(|error| io::Error::new(ErrorKind::Other, error.to_string()))?;
+        let item = find_item(&collection, application, handle)
+            .map_err(|error| io::Error::new(ErrorKind::Other, error.to_string()))?
+            .ok_or(io::Error::new(ErrorKind::NotFound, "not found"))?;
+        let secret_bytes = item
+            .get_secret()
+            .map_err(|error| io::Error::new(ErrorKind::Other, error.to_string()))?;
+        let secret: Secret = serde_json::from_slice(&secret_bytes)
+            .map_err(|error| io::Error::new(ErrorKind::Other, error))?;
+        Ok(secret.counter)
     }
 
     fn get_and_increment_counter(
@@ -114,7 +124,7 @@ impl SecretStore for SecretServiceStore {
         let option = find_item(&collection, application, handle)
             .map_err(|_error| io::Error::new(ErrorKind::Other, "find_item"))?;
         if option.is_none() {
-            return Err(io::Error::new(ErrorKind::Other, "not found"));
+            return Err(io::Error::new(ErrorKind::NotFound, "not found"));
         }
         let item = option.unwrap();
         let secret_bytes = item
@@ -123,7 +133,10 @@ impl SecretStore for SecretServiceStore {
         let mut secret: Secret = serde_json::from_slice(&secret_bytes)
             .map_err(|_error| io::Error::new(ErrorKind::Other, "from_slice"))?;
 
-        secret.counter += 1;
+        secret.counter = secret
+            .counter
+            .checked_add(1)
+            .ok_or(io::Error::new(ErrorKind::Other, "counter exhausted"))?;
 
         let secret_string = serde_json::to_string(&secret)
             .map_err(|error| io::Error::new(ErrorKind::Other, error))?;
@@ -181,6 +194,54 @@ impl SecretStore for SecretServiceStore {
             .map_err(|error| io::Error::new(ErrorKind::Other, error))?;
         Ok(Some(secret.application_key))
     }
+
+    fn delete_application_key(&self, application: &AppId, handle: &KeyHandle) -> io::Result<bool> {
+        let collection = self
+            .service
+            .get_default_collection()
+            .map_err(|error| io::Error::new(ErrorKind::Other, error.to_string()))?;
+        let item = match find_item(&collection, application, handle)
+            .map_err(|error| io::Error::new(ErrorKind::Other, error.to_string()))?
+        {
+            Some(item) => item,
+            None => return Ok(false),
+        };
+        // The counter lives in the same item as the key
+        item.delete()
+            .map_err(|error| io::Error::new(ErrorKind::Other, error.to_string()))?;
+        Ok(true)
+    }
+
+    fn list_application_keys(&self) -> io::Result<Vec<(AppId, KeyHandle)>> {
+        let collection = self
+            .service
+            .get_default_collection()
+            .map_err(|error| io::Error::new(ErrorKind::Other, error.to_string()))?;
+        unlock_if_locked(&collection)?;
+        let items = collection
+            .search_items(schema_attributes())
+            .map_err(|_error| io::Error::new(ErrorKind::Other, "search_items"))?;
+        let mut keys = Vec::new();
+        for item in items {
+            let secret_bytes = item
+                .get_secret()
+                .map_err(|error| io::Error::new(ErrorKind::Other, error.to_string()))?;
+            let secret: Secret = serde_json::from_slice(&secret_bytes)
+                .map_err(|error| io::Error::new(ErrorKind::Other, error))?;
+            keys.push((
+                secret.application_key.application,
+                secret.application_key.handle,
+            ));
+        }
+        Ok(keys)
+    }
+}
+
+fn schema_attributes() -> Vec<(&'static str, &'static str)> {
+    vec![
+        ("application", "com.github.danstiner.rust-u2f"),
+        ("xdg:schema", "com.github.danstiner.rust-u2f"),
+    ]
 }
 
 fn search_attributes(app_id: &AppId, handle: &KeyHandle) -> Vec<(&'static str, String)> {
@@ -0,0 +1,152 @@
+use std::io;
+use std::time::Duration;
+
+use futures::future;
+use futures::prelude::*;
+use tokio_core::reactor::{Handle, Timeout};
+use u2f_core::{AppId, ApprovalError, UserPresence, UserVerification};
+
+/// Wraps another user presence check and fails it with
+/// `ApprovalError::TimedOut` if the user does not respond before the deadline.
+pub struct TimeoutUserPresence<U> {
+    handle: Handle,
+    inner: U,
+    timeout: Duration,
+}
+
+impl<U: UserPresence> TimeoutUserPresence<U> {
+    pub fn new(inner: U, timeout: Duration, handle: &Handle) -> TimeoutUserPresence<U> {
+        TimeoutUserPresence {
+            handle: handle.clone(),
+            inner,
+            timeout,
+        }
+    }
+
+    fn with_timeout<T: 'static>(
+        &self,
+        approval: Box<dyn Future<Item = T, Error = ApprovalError>>,
+    ) -> Box<dyn Future<Item = T, Error = ApprovalError>> {
+        let timeout = match Timeout::new(self.timeout, &self.handle) {
+            Ok(timeout) => timeout,
+            Err(err) => return Box::new(future::err(err.into())),
+        };
+        Box::new(
+            approval
+                .select(
+                    timeout
+                        .from_err()
+                        .and_then(|()| Err(ApprovalError::TimedOut)),
+                )
+                .map(|(approval, _)| approval)
+                .map_err(|(err, _)| err),
+        )
+    }
+}
+
+impl<U: UserPresence> UserPresence for TimeoutUserPresence<U> {
+    fn approve_registration(
+        &self,
+        application: &AppId,
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        self.with_timeout(self.inner.approve_registration(application))
+    }
+
+    fn approve_authentication(
+        &self,
+        application: &AppId,
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        self.with_timeout(self.inner.approve_authentication(application))
+    }
+
+    fn approve_authentication_with_verification(
+        &self,
+        application: &AppId,
+    ) -> Box<dyn Future<Item = Option<UserVerification>, Error = ApprovalError>> {
+        self.with_timeout(
+            self.inner
+                .approve_authentication_with_verification(application),
+        )
+    }
+
+    fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
+        self.inner.wink()
+    }
+
+    fn approve_reset(&self) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        self.with_timeout(self.inner.approve_reset())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use crate::fake_user_presence::FakeUserPresence;
+
+    #[test]
+    fn slow_approval_times_out() {
+        let mut core = Core::new().unwrap();
+        let user_presence = TimeoutUserPresence::new(
+            FakeUserPresence::unresponsive(),
+            Duration::from_millis(10),
+            &core.handle(),
+        );
+
+        let result = core.run(user_presence.approve_authentication(&AppId::from_bytes(&[0u8; 32])));
+
+        assert!(matches!(result, Err(ApprovalError::TimedOut)));
+    }
+
+    #[test]
+    fn prompt_approval_is_passed_through() {
+        let mut core = Core::new().unwrap();
+        let user_presence = TimeoutUserPresence::new(
+            FakeUserPresence::approving(),
+            Duration::from_secs(10),
+            &core.handle(),
+        );
+
+        let user_present = core
+            .run(user_presence.approve_registration(&AppId::from_bytes(&[0u8; 32])))
+            .unwrap();
+
+        assert!(user_present);
+    }
+
+    #[test]
+    fn slow_verification_times_out() {
+        let mut core = Core::new().unwrap();
+        let user_presence = TimeoutUserPresence::new(
+            FakeUserPresence::unresponsive().verifying(),
+            Duration::from_millis(10),
+            &core.handle(),
+        );
+
+        let result = core.run(
+            user_presence.approve_authentication_with_verification(&AppId::from_bytes(&[0u8; 32])),
+        );
+
+        assert!(matches!(result, Err(ApprovalError::TimedOut)));
+    }
+
+    #[test]
+    fn verification_is_passed_through() {
+        let mut core = Core::new().unwrap();
+        let user_presence = TimeoutUserPresence::new(
+            FakeUserPresence::approving().verifying(),
+            Duration::from_secs(10),
+            &core.handle(),
+        );
+
+        let verification = core
+            .run(
+                user_presence
+                    .approve_authentication_with_verification(&AppId::from_bytes(&[0u8; 32])),
+            )
+            .unwrap();
+
+        assert_eq!(verification, Some(UserVerification::Verified));
+    }
+}
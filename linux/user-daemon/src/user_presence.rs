@@ -7,7 +7,7 @@ use notify_rust::{self, Notification, NotificationHint, NotificationUrgency};
 use slog::Logger;
 use time::Duration;
 use tokio_core::reactor::Handle;
-use u2f_core::{try_reverse_app_id, AppId, UserPresence};
+use u2f_core::{try_reverse_app_id, AppId, ApprovalError, UserPresence};
 
 const APPNAME: &str = "SoftU2F";
 const HINT_CATEGORY: &str = "device";
@@ -34,13 +34,16 @@ impl NotificationUserPresence {
         }
     }
 
-    fn test_user_presence(&self, message: &str) -> Box<dyn Future<Item = bool, Error = io::Error>> {
+    fn test_user_presence(
+        &self,
+        message: &str,
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
         debug!(self.logger, "test_user_presence"; "message" => message);
 
         let body = message.to_owned();
         let logger = self.logger.clone();
 
-        Box::new(self.executor.spawn_fn(move || {
+        Box::new(self.executor.spawn_fn(move || -> Result<bool, ApprovalError> {
             let mut notification = Notification::new();
             notification
                 .appname(APPNAME)
@@ -54,7 +57,8 @@ impl NotificationUserPresence {
                 .timeout(TIMEOUT.num_milliseconds() as i32);
 
             let mut default_means_user_present = false;
-            let server_info = notify_rust::get_server_information().unwrap();
+            let server_info = notify_rust::get_server_information()
+                .map_err(|err| ApprovalError::Unavailable(err.to_string()))?;
             if server_info.name == "notify-osd" && server_info.version == "1.0" {
                 // See https://github.com/danstiner/softu2f-linux/issues/12
                 debug!(logger, "Detected notify-osd server, applying workaround"; "server_info" => ?server_info);
@@ -65,7 +69,9 @@ impl NotificationUserPresence {
                 notification.action("deny", "Deny");
             }
 
-            let notify_handle = notification.show().unwrap();
+            let notify_handle = notification
+                .show()
+                .map_err(|err| ApprovalError::Unavailable(err.to_string()))?;
 
             let mut action = String::new();
             notify_handle.wait_for_action(|a| action = a.to_owned());
@@ -89,7 +95,7 @@ impl UserPresence for NotificationUserPresence {
     fn approve_registration(
         &self,
         application: &AppId,
-    ) -> Box<dyn Future<Item = bool, Error = io::Error>> {
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
         let site_name = try_reverse_app_id(application).unwrap_or(String::from("site"));
         let message = format!("Register with {}", site_name);
         self.test_user_presence(&message)
@@ -98,7 +104,7 @@ impl UserPresence for NotificationUserPresence {
     fn approve_authentication(
         &self,
         application: &AppId,
-    ) -> Box<dyn Future<Item = bool, Error = io::Error>> {
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
         let site_name = try_reverse_app_id(application).unwrap_or(String::from("site"));
         let message = format!("Authenticate with {}", site_name);
         self.test_user_presence(&message)
@@ -118,4 +124,8 @@ impl UserPresence for NotificationUserPresence {
             .timeout(TIMEOUT.num_milliseconds() as i32);
         Box::new(future::ok(()))
     }
+
+    fn approve_reset(&self) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        self.test_user_presence("Reset, deleting all credentials")
+    }
 }
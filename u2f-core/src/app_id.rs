@@ -1,7 +1,9 @@
 use std::result::Result;
 
 use hex;
+use ring::digest;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json;
 use crate::serde_base64::{from_base64, to_base64};
 use slog;
 use subtle::ConstantTimeEq;
@@ -16,6 +18,12 @@ impl AppId {
         AppId(bytes)
     }
 
+    /// Derives the application parameter from an AppID URL or WebAuthn
+    /// relying party id, the same way a client would.
+    pub fn from_app_id(app_id: &str) -> AppId {
+        AppId::from_bytes(digest::digest(&digest::SHA256, app_id.as_bytes()).as_ref())
+    }
+
     pub fn eq_consttime(&self, other: &AppId) -> bool {
         self.0.ct_eq(&other.0).unwrap_u8() == 1
     }
@@ -51,6 +59,28 @@ impl<'de> Deserialize<'de> for AppId {
     }
 }
 
+/// The parts of U2F client data (`{"typ":..,"challenge":..,"origin":..}`)
+/// that determine the application parameter.
+#[derive(Deserialize)]
+struct ClientData {
+    origin: String,
+    #[serde(rename = "appId")]
+    app_id: Option<String>,
+}
+
+/// Checks that `client_data` was produced for the application `expected`, by
+/// hashing its AppID, or its origin if it has none. A mismatch means the
+/// request was relayed from another site. Malformed client data never matches.
+pub fn check_application_binding(client_data: &[u8], expected: &AppId) -> bool {
+    match serde_json::from_slice::<ClientData>(client_data) {
+        Ok(client_data) => {
+            let app_id = client_data.app_id.unwrap_or(client_data.origin);
+            AppId::from_app_id(&app_id).eq_consttime(expected)
+        }
+        Err(_) => false,
+    }
+}
+
 impl slog::Value for AppId {
     fn serialize(
         &self,
@@ -66,3 +96,54 @@ impl slog::Value for AppId {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_app_id_is_sha256_of_app_id() {
+        assert_eq!(
+            hex::encode(AppId::from_app_id("abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    const CLIENT_DATA: &[u8] =
+        br#"{"typ":"navigator.id.finishEnrollment","challenge":"vqrS6WXDe1JUs5_c3i4-LkKIHRr-3XVb3azuA5TifHo","origin":"https://example.com"}"#;
+
+    #[test]
+    fn application_binding_with_matching_origin() {
+        assert!(check_application_binding(
+            CLIENT_DATA,
+            &AppId::from_app_id("https://example.com")
+        ));
+    }
+
+    #[test]
+    fn application_binding_with_other_origin_fails() {
+        assert!(!check_application_binding(
+            CLIENT_DATA,
+            &AppId::from_app_id("https://example.org")
+        ));
+    }
+
+    #[test]
+    fn application_binding_prefers_app_id_over_origin() {
+        let client_data =
+            br#"{"origin":"https://example.com","appId":"https://example.com/app-id.json"}"#;
+
+        assert!(check_application_binding(
+            client_data,
+            &AppId::from_app_id("https://example.com/app-id.json")
+        ));
+    }
+
+    #[test]
+    fn application_binding_with_malformed_json_fails() {
+        let expected = AppId::from_app_id("https://example.com");
+
+        assert!(!check_application_binding(b"{\"origin\":", &expected));
+        assert!(!check_application_binding(b"{}", &expected));
+    }
+}
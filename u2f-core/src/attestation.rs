@@ -1,24 +1,124 @@
+use openssl::bn::BigNumContext;
+use openssl::ec::{EcKey, PointConversionForm};
+use openssl::error::ErrorStack;
+use openssl::pkey::PKey;
 use openssl::x509::X509;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt::{self, Debug};
 
 use crate::private_key::PrivateKey;
 
-#[derive(Clone)]
+quick_error! {
+    #[derive(Debug)]
+    pub enum LoadAttestationError {
+        Parse(err: ErrorStack) {
+            from()
+            cause(err)
+            display("Unable to parse attestation certificate or key: {}", err)
+        }
+        NoCertificate {
+            display("No attestation certificate found")
+        }
+        KeyMismatch {
+            display("Attestation key does not match the certificate")
+        }
+    }
+}
+
+/// How a registration vouches for the key it returns.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AttestationMode {
+    /// Self attestation: no certificate, the registration is signed with the
+    /// new key itself, so it reveals nothing about the device.
+    None,
+    /// Batch attestation: signed with the attestation key, whose certificate
+    /// is included.
+    Direct,
+}
+
+#[derive(Clone, Debug)]
 pub struct Attestation {
     pub(crate) certificate: AttestationCertificate,
     pub(crate) key: PrivateKey,
 }
 
+impl Attestation {
+    /// Loads an existing attestation, such as a vendor batch key, instead of
+    /// a self-signed one. `certificate_pem` starts with the attestation
+    /// certificate, any certificates after it are kept as intermediates.
+    pub fn from_pem(
+        certificate_pem: &[u8],
+        key_pem: &[u8],
+    ) -> Result<Attestation, LoadAttestationError> {
+        let mut certificates = X509::stack_from_pem(certificate_pem)?.into_iter();
+        let leaf = certificates
+            .next()
+            .ok_or(LoadAttestationError::NoCertificate)?;
+        let key = EcKey::private_key_from_pem(key_pem)?;
+        let pkey = PKey::from_ec_key(key.clone())?;
+        if !leaf.public_key()?.public_eq(&pkey) {
+            return Err(LoadAttestationError::KeyMismatch);
+        }
+        Ok(Attestation {
+            certificate: AttestationCertificate::new(leaf)?
+                .with_intermediates(certificates.collect()),
+            key: PrivateKey(key),
+        })
+    }
+}
+
+/// The attestation certificate, optionally followed by the intermediate
+/// certificates needed to chain it to a vendor root.
 #[derive(Clone)]
-pub struct AttestationCertificate(pub(crate) X509);
+pub struct AttestationCertificate {
+    pub(crate) leaf: X509,
+    pub(crate) intermediates: Vec<X509>,
+    public_key: Vec<u8>,
+}
 
 impl AttestationCertificate {
+    pub(crate) fn new(leaf: X509) -> Result<AttestationCertificate, ErrorStack> {
+        let key = leaf.public_key()?.ec_key()?;
+        let mut ctx = BigNumContext::new()?;
+        let public_key =
+            key.public_key()
+                .to_bytes(key.group(), PointConversionForm::UNCOMPRESSED, &mut ctx)?;
+        Ok(AttestationCertificate {
+            leaf,
+            intermediates: Vec::new(),
+            public_key,
+        })
+    }
+
     pub(crate) fn from_pem(pem: &str) -> AttestationCertificate {
-        AttestationCertificate(X509::from_pem(pem.as_bytes()).unwrap())
+        AttestationCertificate::new(X509::from_pem(pem.as_bytes()).unwrap()).unwrap()
+    }
+
+    /// Intermediates are emitted after the leaf in the given order, each one
+    /// should be issued by the next.
+    pub fn with_intermediates(mut self, intermediates: Vec<X509>) -> AttestationCertificate {
+        self.intermediates = intermediates;
+        self
     }
 
-    pub(crate) fn to_der(&self) -> Vec<u8> {
-        self.0.to_der().unwrap()
+    /// The leaf certificate's public key as an uncompressed EC point, for
+    /// relying parties that pin the attestation key rather than the
+    /// certificate.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// DER of the leaf certificate alone.
+    pub fn to_der(&self) -> Vec<u8> {
+        self.leaf.to_der().unwrap()
+    }
+
+    /// DER of the leaf followed by each intermediate.
+    pub(crate) fn chain_to_der(&self) -> Vec<Vec<u8>> {
+        std::iter::once(&self.leaf)
+            .chain(self.intermediates.iter())
+            .map(|certificate| certificate.to_der().unwrap())
+            .collect()
     }
 }
 
@@ -27,3 +127,124 @@ impl Debug for AttestationCertificate {
         write!(f, "AttestationCertificate")
     }
 }
+
+/// Serialized as a list of base64 encoded DER certificates, leaf first.
+impl Serialize for AttestationCertificate {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let chain: Vec<String> = self.chain_to_der().iter().map(base64::encode).collect();
+        chain.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for AttestationCertificate {
+    fn deserialize<D>(deserializer: D) -> Result<AttestationCertificate, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let mut certificates = Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|encoded| {
+                let der = base64::decode(encoded).map_err(|err| Error::custom(err.to_string()))?;
+                X509::from_der(&der).map_err(|err| Error::custom(err.to_string()))
+            })
+            .collect::<Result<Vec<X509>, D::Error>>()?
+            .into_iter();
+        let leaf = certificates
+            .next()
+            .ok_or_else(|| Error::custom("no attestation certificate"))?;
+        let certificate =
+            AttestationCertificate::new(leaf).map_err(|err| Error::custom(err.to_string()))?;
+        Ok(certificate.with_intermediates(certificates.collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::ec::EcPoint;
+
+    use crate::openssl_crypto::OpenSSLCryptoOperations;
+    use crate::public_key::PublicKey;
+    use crate::self_signed_attestation::{
+        generate_self_signed_attestation, SELF_SIGNED_ATTESTATION_CERTIFICATE_PEM,
+        SELF_SIGNED_ATTESTATION_KEY_PEM,
+    };
+    use crate::CryptoOperations;
+
+    use super::*;
+
+    #[test]
+    fn from_pem_with_matching_key_succeeds() {
+        let attestation = Attestation::from_pem(
+            SELF_SIGNED_ATTESTATION_CERTIFICATE_PEM.as_bytes(),
+            SELF_SIGNED_ATTESTATION_KEY_PEM.as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            attestation.certificate.to_der(),
+            AttestationCertificate::from_pem(SELF_SIGNED_ATTESTATION_CERTIFICATE_PEM).to_der()
+        );
+        assert!(attestation.certificate.intermediates.is_empty());
+    }
+
+    #[test]
+    fn from_pem_keeps_following_certificates_as_intermediates() {
+        let intermediate = generate_self_signed_attestation().unwrap().certificate.leaf;
+        let mut certificate_pem = SELF_SIGNED_ATTESTATION_CERTIFICATE_PEM.as_bytes().to_vec();
+        certificate_pem.push(b'\n');
+        certificate_pem.extend_from_slice(&intermediate.to_pem().unwrap());
+
+        let attestation =
+            Attestation::from_pem(&certificate_pem, SELF_SIGNED_ATTESTATION_KEY_PEM.as_bytes())
+                .unwrap();
+
+        assert_eq!(
+            attestation.certificate.chain_to_der()[1],
+            intermediate.to_der().unwrap()
+        );
+    }
+
+    #[test]
+    fn from_pem_with_other_key_errors() {
+        let other_key = generate_self_signed_attestation().unwrap().key;
+        let other_key_pem = other_key.0.private_key_to_pem().unwrap();
+
+        assert_matches!(
+            Attestation::from_pem(
+                SELF_SIGNED_ATTESTATION_CERTIFICATE_PEM.as_bytes(),
+                &other_key_pem
+            ),
+            Err(LoadAttestationError::KeyMismatch)
+        );
+    }
+
+    #[test]
+    fn from_pem_without_certificate_errors() {
+        assert_matches!(
+            Attestation::from_pem(b"", SELF_SIGNED_ATTESTATION_KEY_PEM.as_bytes()),
+            Err(LoadAttestationError::NoCertificate)
+        );
+    }
+
+    #[test]
+    fn attestation_public_key_matches_generated_certificate() {
+        let attestation = generate_self_signed_attestation().unwrap();
+        let operations = OpenSSLCryptoOperations::new(attestation.clone());
+
+        let public_key = operations.attestation_public_key();
+
+        assert_eq!(public_key, PublicKey::from_key(&attestation.key).to_raw());
+        let certificate = X509::from_der(&attestation.certificate.to_der()).unwrap();
+        let certificate_key = certificate.public_key().unwrap().ec_key().unwrap();
+        let group = certificate_key.group();
+        let mut ctx = BigNumContext::new().unwrap();
+        let point = EcPoint::from_bytes(group, &public_key, &mut ctx).unwrap();
+        assert!(point
+            .eq(group, certificate_key.public_key(), &mut ctx)
+            .unwrap());
+    }
+}
@@ -0,0 +1,129 @@
+//! Portable backups of every credential in a store.
+//!
+//! A backup starts with `MAGIC` and a version byte, followed by the number of
+//! credentials and then each credential as:
+//! application (32 bytes) || key handle length (1 byte) || key handle ||
+//! counter (4 bytes) || private key length (2 bytes) || private key (DER).
+//! All integers are big endian. Private keys are not encrypted, callers are
+//! responsible for protecting the backup. Non-exportable keys are left out.
+
+use std::io::{self, Read};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use openssl::ec::EcKey;
+
+use crate::app_id::AppId;
+use crate::application_key::ApplicationKey;
+use crate::key_handle::KeyHandle;
+use crate::private_key::{PrivateKey, SecretBytes};
+
+use super::Counter;
+use super::SecretStore;
+
+const MAGIC: &[u8; 4] = b"U2FB";
+const VERSION: u8 = 1;
+
+pub(crate) fn export(store: &(impl SecretStore + ?Sized)) -> io::Result<Vec<u8>> {
+    let mut credentials = Vec::new();
+    for (application, handle) in store.list_application_keys()? {
+        let application_key = store
+            .retrieve_application_key(&application, &handle)?
+            .ok_or_else(|| io::Error::other("listed key is missing"))?;
+        if !application_key.non_exportable {
+            credentials.push((application, handle, application_key));
+        }
+    }
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    bytes.write_u32::<BigEndian>(credentials.len() as u32)?;
+    for (application, handle, application_key) in credentials {
+        let counter = store.retrieve_counter(&application, &handle)?;
+        let key_der = SecretBytes(
+            application_key
+                .key()
+                .0
+                .private_key_to_der()
+                .map_err(io::Error::other)?,
+        );
+
+        bytes.extend_from_slice(application.as_ref());
+        bytes.push(handle.len() as u8);
+        bytes.extend_from_slice(handle.as_ref());
+        bytes.write_u32::<BigEndian>(counter)?;
+        bytes.write_u16::<BigEndian>(key_der.len() as u16)?;
+        bytes.extend_from_slice(&key_der);
+    }
+    Ok(bytes)
+}
+
+/// Parses a backup made by `SecretStore::export` into its credentials and
+/// their counters, for stores to add however they keep counters.
+pub fn read_backup(backup: &[u8]) -> io::Result<Vec<(ApplicationKey, Counter)>> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut reader = backup;
+
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a backup"));
+    }
+    if reader.read_u8()? != VERSION {
+        return Err(invalid("unsupported backup version"));
+    }
+
+    let count = reader.read_u32::<BigEndian>()?;
+    let mut credentials = Vec::new();
+    for _ in 0..count {
+        let mut application = [0u8; 32];
+        reader.read_exact(&mut application)?;
+        let mut handle = vec![0u8; reader.read_u8()? as usize];
+        reader.read_exact(&mut handle)?;
+        let counter = reader.read_u32::<BigEndian>()?;
+        let mut key_der = SecretBytes(vec![0u8; reader.read_u16::<BigEndian>()? as usize]);
+        reader.read_exact(&mut key_der.0)?;
+        let key = EcKey::private_key_from_der(&key_der).map_err(|_| invalid("invalid key"))?;
+
+        let application_key = ApplicationKey::new(
+            AppId::from_bytes(&application),
+            KeyHandle::from(&handle),
+            PrivateKey(key),
+        );
+        credentials.push((application_key, counter));
+    }
+    if !reader.is_empty() {
+        return Err(invalid("trailing data after backup"));
+    }
+    Ok(credentials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_backup_with_wrong_magic_errors() {
+        let error = read_backup(b"NOPE\x01\x00\x00\x00\x00").unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_backup_with_future_version_errors() {
+        let error = read_backup(b"U2FB\x02\x00\x00\x00\x00").unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_backup_with_truncated_credential_errors() {
+        let error = read_backup(b"U2FB\x01\x00\x00\x00\x01\x00").unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn read_empty_backup() {
+        assert!(read_backup(b"U2FB\x01\x00\x00\x00\x00").unwrap().is_empty());
+    }
+}
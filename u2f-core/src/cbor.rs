@@ -0,0 +1,193 @@
+//! Just enough CBOR (RFC 7049) to build CTAP2 responses.
+//!
+//! Map entries are written in the order given, callers are responsible for
+//! following the CTAP2 canonical ordering of keys.
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+const MAJOR_UNSIGNED: u8 = 0;
+const MAJOR_NEGATIVE: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+impl Value {
+    pub(crate) fn text(text: &str) -> Value {
+        Value::Text(String::from(text))
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes);
+        bytes
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) {
+        match self {
+            Value::Integer(value) if *value >= 0 => {
+                write_head(bytes, MAJOR_UNSIGNED, *value as u64)
+            }
+            Value::Integer(value) => write_head(bytes, MAJOR_NEGATIVE, (-1 - *value) as u64),
+            Value::Bytes(value) => {
+                write_head(bytes, MAJOR_BYTES, value.len() as u64);
+                bytes.extend_from_slice(value);
+            }
+            Value::Text(value) => {
+                write_head(bytes, MAJOR_TEXT, value.len() as u64);
+                bytes.extend_from_slice(value.as_bytes());
+            }
+            Value::Array(values) => {
+                write_head(bytes, MAJOR_ARRAY, values.len() as u64);
+                for value in values {
+                    value.write(bytes);
+                }
+            }
+            Value::Map(entries) => {
+                write_head(bytes, MAJOR_MAP, entries.len() as u64);
+                for (key, value) in entries {
+                    key.write(bytes);
+                    value.write(bytes);
+                }
+            }
+        }
+    }
+}
+
+/// Writes the initial byte and argument using the shortest encoding.
+fn write_head(bytes: &mut Vec<u8>, major_type: u8, argument: u64) {
+    let major_type = major_type << 5;
+    if argument < 24 {
+        bytes.push(major_type | argument as u8);
+    } else if argument <= u64::from(u8::MAX) {
+        bytes.push(major_type | 24);
+        bytes.push(argument as u8);
+    } else if argument <= u64::from(u16::MAX) {
+        bytes.push(major_type | 25);
+        bytes.write_u16::<BigEndian>(argument as u16).unwrap();
+    } else if argument <= u64::from(u32::MAX) {
+        bytes.push(major_type | 26);
+        bytes.write_u32::<BigEndian>(argument as u32).unwrap();
+    } else {
+        bytes.push(major_type | 27);
+        bytes.write_u64::<BigEndian>(argument).unwrap();
+    }
+}
+
+/// Decodes a single value, only used to check encoded responses in tests.
+#[cfg(test)]
+pub(crate) fn decode(bytes: &[u8]) -> Result<Value, ()> {
+    let (value, rest) = decode_value(bytes)?;
+    if !rest.is_empty() {
+        return Err(());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+fn decode_value(bytes: &[u8]) -> Result<(Value, &[u8]), ()> {
+    let (major_type, argument, mut rest) = decode_head(bytes)?;
+    let value = match major_type {
+        MAJOR_UNSIGNED => Value::Integer(argument as i64),
+        MAJOR_NEGATIVE => Value::Integer(-1 - argument as i64),
+        MAJOR_BYTES | MAJOR_TEXT => {
+            if rest.len() < argument as usize {
+                return Err(());
+            }
+            let (data, remaining) = rest.split_at(argument as usize);
+            rest = remaining;
+            if major_type == MAJOR_BYTES {
+                Value::Bytes(data.to_vec())
+            } else {
+                Value::Text(String::from_utf8(data.to_vec()).map_err(|_| ())?)
+            }
+        }
+        MAJOR_ARRAY => {
+            let mut values = Vec::new();
+            for _ in 0..argument {
+                let (value, remaining) = decode_value(rest)?;
+                values.push(value);
+                rest = remaining;
+            }
+            Value::Array(values)
+        }
+        MAJOR_MAP => {
+            let mut entries = Vec::new();
+            for _ in 0..argument {
+                let (key, remaining) = decode_value(rest)?;
+                let (value, remaining) = decode_value(remaining)?;
+                entries.push((key, value));
+                rest = remaining;
+            }
+            Value::Map(entries)
+        }
+        _ => return Err(()),
+    };
+    Ok((value, rest))
+}
+
+#[cfg(test)]
+fn decode_head(bytes: &[u8]) -> Result<(u8, u64, &[u8]), ()> {
+    let (&initial, rest) = bytes.split_first().ok_or(())?;
+    let major_type = initial >> 5;
+    let (argument_len, argument) = match initial & 0x1f {
+        value @ 0..=23 => (0, u64::from(value)),
+        24 => (1, 0),
+        25 => (2, 0),
+        26 => (4, 0),
+        27 => (8, 0),
+        _ => return Err(()),
+    };
+    if rest.len() < argument_len {
+        return Err(());
+    }
+    let (argument_bytes, rest) = rest.split_at(argument_len);
+    let argument = argument_bytes.iter().fold(argument, |argument, byte| {
+        (argument << 8) | u64::from(*byte)
+    });
+    Ok((major_type, argument, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_rfc7049_examples() {
+        assert_eq!(Value::Integer(10).to_bytes(), vec![0x0a]);
+        assert_eq!(Value::Integer(500).to_bytes(), vec![0x19, 0x01, 0xf4]);
+        assert_eq!(Value::Integer(-7).to_bytes(), vec![0x26]);
+        assert_eq!(
+            Value::Bytes(vec![1, 2, 3, 4]).to_bytes(),
+            vec![0x44, 1, 2, 3, 4]
+        );
+        assert_eq!(Value::text("a").to_bytes(), vec![0x61, 0x61]);
+        assert_eq!(
+            Value::Map(vec![(
+                Value::Integer(1),
+                Value::Array(vec![Value::Integer(2)])
+            )])
+            .to_bytes(),
+            vec![0xa1, 0x01, 0x81, 0x02]
+        );
+    }
+
+    #[test]
+    fn decode_round_trips() {
+        let value = Value::Map(vec![
+            (Value::text("fmt"), Value::text("fido-u2f")),
+            (Value::Integer(-3), Value::Bytes(vec![7u8; 300])),
+        ]);
+
+        assert_eq!(decode(&value.to_bytes()), Ok(value));
+    }
+}
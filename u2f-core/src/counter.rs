@@ -0,0 +1,71 @@
+use std::error::Error;
+use std::fmt;
+
+use super::Counter;
+
+/// A counter that did not increase since the previous authentication, which
+/// suggests the key was copied to another token.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CloneDetected {
+    pub previous: Counter,
+    pub presented: Counter,
+}
+
+impl fmt::Display for CloneDetected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Counter {} is not greater than previously seen {}, token may be cloned",
+            self.presented, self.previous
+        )
+    }
+}
+
+impl Error for CloneDetected {}
+
+/// For relying parties: checks the counter from an authentication response
+/// against the last one stored for the credential. Tokens only ever
+/// increase their counter, so anything else is treated as a possible clone.
+pub fn verify_counter(previous: Counter, presented: Counter) -> Result<(), CloneDetected> {
+    if presented > previous {
+        Ok(())
+    } else {
+        Err(CloneDetected {
+            previous,
+            presented,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn increased_counter_is_accepted() {
+        assert_eq!(verify_counter(4, 5), Ok(()));
+        assert_eq!(verify_counter(4, 100), Ok(()));
+    }
+
+    #[test]
+    fn equal_counter_is_rejected() {
+        assert_eq!(
+            verify_counter(4, 4),
+            Err(CloneDetected {
+                previous: 4,
+                presented: 4,
+            })
+        );
+    }
+
+    #[test]
+    fn decreased_counter_is_rejected() {
+        assert_eq!(
+            verify_counter(4, 3),
+            Err(CloneDetected {
+                previous: 4,
+                presented: 3,
+            })
+        );
+    }
+}
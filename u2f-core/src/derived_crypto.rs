@@ -0,0 +1,298 @@
+use std::sync::Mutex;
+
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey, EcPoint};
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::rand::rand_bytes;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use ring::hkdf;
+use subtle::ConstantTimeEq;
+
+use crate::app_id::AppId;
+use crate::application_key::ApplicationKey;
+use crate::attestation::{Attestation, AttestationCertificate};
+use crate::key_handle::{KeyHandle, KeyHandleVersion};
+use crate::openssl_crypto::{lock, OpenSSLCryptoOperations};
+use crate::private_key::{PrivateKey, SecretBytes};
+
+use super::CryptoOperations;
+use super::KeyError;
+use super::SignError;
+use super::Signature;
+
+const SEED_LEN: usize = 32;
+const SALT_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+/// Extra bytes beyond the 32 byte group order keep the reduced scalar unbiased
+const SCALAR_SEED_LEN: usize = 48;
+
+/// Derives application keys from a device seed instead of storing them.
+///
+/// A key handle is `version || salt || tag`, with a fresh random salt per
+/// registration.
+/// The private key is HKDF-SHA256 of the seed with that salt and the
+/// application parameter, so it can be recomputed from the handle alone and
+/// every key survives as long as the seed does. The tag is derived the same
+/// way and proves the handle was issued by this seed for this application.
+pub struct DerivedCryptoOperations {
+    deterministic_key_handles: bool,
+    operations: OpenSSLCryptoOperations,
+    seed: Mutex<SecretBytes>,
+}
+
+impl DerivedCryptoOperations {
+    pub fn new(attestation: Attestation, seed: [u8; SEED_LEN]) -> DerivedCryptoOperations {
+        DerivedCryptoOperations {
+            deterministic_key_handles: false,
+            operations: OpenSSLCryptoOperations::new(attestation),
+            seed: Mutex::new(SecretBytes(seed.to_vec())),
+        }
+    }
+
+    /// Derive the salt from the seed and application parameter instead of
+    /// picking it at random, so every registration for an application gets
+    /// the same key handle and key. Anyone holding just the seed can then
+    /// recreate the credentials, but a relying party also sees the same
+    /// credential each time the token registers with it.
+    pub fn with_deterministic_key_handles(mut self) -> DerivedCryptoOperations {
+        self.deterministic_key_handles = true;
+        self
+    }
+
+    fn expand(&self, salt: &[u8], label: &[u8], application: &AppId, out: &mut [u8]) {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(&lock(&self.seed));
+        prk.expand(&[label, application.as_ref()], OutputLen(out.len()))
+            .and_then(|okm| okm.fill(out))
+            .expect("output length is within HKDF limits");
+    }
+
+    fn tag(&self, salt: &[u8], application: &AppId) -> [u8; TAG_LEN] {
+        let mut tag = [0u8; TAG_LEN];
+        self.expand(salt, b"key handle tag", application, &mut tag);
+        tag
+    }
+
+    fn derive_key(&self, salt: &[u8], application: &AppId) -> Result<PrivateKey, ErrorStack> {
+        let mut scalar_seed = SecretBytes(vec![0u8; SCALAR_SEED_LEN]);
+        self.expand(salt, b"application key", application, &mut scalar_seed.0);
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let mut ctx = BigNumContext::new()?;
+        let mut order = BigNum::new()?;
+        group.order(&mut order, &mut ctx)?;
+        // Map into [1, order) so the scalar is never zero
+        let mut order_minus_one = BigNum::new()?;
+        order_minus_one.checked_sub(&order, BigNum::from_u32(1)?.as_ref())?;
+        let mut private_number = BigNum::new()?;
+        private_number.nnmod(
+            BigNum::from_slice(&scalar_seed)?.as_ref(),
+            &order_minus_one,
+            &mut ctx,
+        )?;
+        private_number.add_word(1)?;
+
+        let mut public_point = EcPoint::new(&group)?;
+        public_point.mul_generator2(&group, &private_number, &mut ctx)?;
+        let ec_key = EcKey::from_private_components(&group, &private_number, &public_point)?;
+        Ok(PrivateKey(ec_key))
+    }
+}
+
+impl CryptoOperations for DerivedCryptoOperations {
+    fn attest(&self, data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
+        self.operations.attest(data)
+    }
+
+    fn generate_application_key(&self, application: &AppId) -> Result<ApplicationKey, KeyError> {
+        let mut salt = [0u8; SALT_LEN];
+        if self.deterministic_key_handles {
+            self.expand(&[], b"key handle salt", application, &mut salt);
+        } else {
+            OsRng.try_fill_bytes(&mut salt).map_err(KeyError::Random)?;
+        }
+        let key = self
+            .derive_key(&salt, application)
+            .map_err(KeyError::Generate)?;
+        let mut handle = salt.to_vec();
+        handle.extend_from_slice(&self.tag(&salt, application));
+        Ok(ApplicationKey::new(
+            *application,
+            KeyHandle::tagged(KeyHandleVersion::Derived, &handle),
+            key,
+        ))
+    }
+
+    /// A random key under a random salt and tag, which this seed will not
+    /// vouch for.
+    fn preview_application_key(&self, application: &AppId) -> Result<ApplicationKey, KeyError> {
+        let mut handle = [0u8; SALT_LEN + TAG_LEN];
+        OsRng
+            .try_fill_bytes(&mut handle)
+            .map_err(KeyError::Random)?;
+        let mut application_key = self.operations.preview_application_key(application)?;
+        application_key.handle = KeyHandle::tagged(KeyHandleVersion::Derived, &handle);
+        Ok(application_key)
+    }
+
+    fn get_attestation_certificate(&self) -> AttestationCertificate {
+        self.operations.get_attestation_certificate()
+    }
+
+    /// Replaces the seed with a new random one, every key derived from the
+    /// old seed is gone for good.
+    fn reset(&self) -> Result<(), KeyError> {
+        let mut seed = SecretBytes(vec![0u8; SEED_LEN]);
+        rand_bytes(&mut seed.0).map_err(KeyError::Rotate)?;
+        *lock(&self.seed) = seed;
+        Ok(())
+    }
+
+    fn unwrap_application_key(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> Result<Option<ApplicationKey>, KeyError> {
+        // Handles derived before the version byte was added have no tag
+        let bytes = match handle.body(KeyHandleVersion::Derived) {
+            Some(body) if body.len() == SALT_LEN + TAG_LEN => body,
+            _ => handle.as_ref(),
+        };
+        if bytes.len() != SALT_LEN + TAG_LEN {
+            return Ok(None);
+        }
+        let (salt, tag) = bytes.split_at(SALT_LEN);
+        if self.tag(salt, application).ct_eq(tag).unwrap_u8() != 1 {
+            return Ok(None);
+        }
+        let key = self
+            .derive_key(salt, application)
+            .map_err(KeyError::Generate)?;
+        Ok(Some(ApplicationKey::new(*application, handle.clone(), key)))
+    }
+
+    fn sign(&self, key: &PrivateKey, data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
+        self.operations.sign(key, data)
+    }
+}
+
+struct OutputLen(usize);
+
+impl hkdf::KeyType for OutputLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::public_key::PublicKey;
+    use crate::self_signed_attestation::self_signed_attestation;
+
+    use super::*;
+
+    fn public_key_bytes(application_key: &ApplicationKey) -> Vec<u8> {
+        PublicKey::from_key(application_key.key()).to_raw()
+    }
+
+    #[test]
+    fn rederived_key_matches_generated_key() {
+        let application = AppId([1u8; 32]);
+        let operations = DerivedCryptoOperations::new(self_signed_attestation(), [7u8; 32]);
+        let generated = operations.generate_application_key(&application).unwrap();
+
+        let rederived = DerivedCryptoOperations::new(self_signed_attestation(), [7u8; 32])
+            .unwrap_application_key(&application, &generated.handle)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(public_key_bytes(&rederived), public_key_bytes(&generated));
+    }
+
+    #[test]
+    fn untagged_handles_are_still_rederived() {
+        let application = AppId([1u8; 32]);
+        let operations = DerivedCryptoOperations::new(self_signed_attestation(), [7u8; 32]);
+        let generated = operations.generate_application_key(&application).unwrap();
+        assert_eq!(generated.handle.version(), Some(KeyHandleVersion::Derived));
+
+        let untagged = KeyHandle::from(generated.handle.body(KeyHandleVersion::Derived).unwrap());
+        let rederived = operations
+            .unwrap_application_key(&application, &untagged)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(public_key_bytes(&rederived), public_key_bytes(&generated));
+    }
+
+    #[test]
+    fn generated_keys_differ_per_registration() {
+        let application = AppId([1u8; 32]);
+        let operations = DerivedCryptoOperations::new(self_signed_attestation(), [7u8; 32]);
+
+        let first = operations.generate_application_key(&application).unwrap();
+        let second = operations.generate_application_key(&application).unwrap();
+
+        assert_ne!(public_key_bytes(&first), public_key_bytes(&second));
+    }
+
+    #[test]
+    fn deterministic_key_handles_are_recoverable_from_seed() {
+        let application = AppId([1u8; 32]);
+        let first = DerivedCryptoOperations::new(self_signed_attestation(), [7u8; 32])
+            .with_deterministic_key_handles()
+            .generate_application_key(&application)
+            .unwrap();
+        let second = DerivedCryptoOperations::new(self_signed_attestation(), [7u8; 32])
+            .with_deterministic_key_handles()
+            .generate_application_key(&application)
+            .unwrap();
+
+        assert_eq!(first.handle, second.handle);
+        assert_eq!(public_key_bytes(&first), public_key_bytes(&second));
+    }
+
+    #[test]
+    fn deterministic_key_handles_differ_per_application() {
+        let operations = DerivedCryptoOperations::new(self_signed_attestation(), [7u8; 32])
+            .with_deterministic_key_handles();
+
+        let first = operations
+            .generate_application_key(&AppId([1u8; 32]))
+            .unwrap();
+        let second = operations
+            .generate_application_key(&AppId([2u8; 32]))
+            .unwrap();
+
+        assert_ne!(first.handle, second.handle);
+    }
+
+    #[test]
+    fn unwrap_for_other_application_is_none() {
+        let operations = DerivedCryptoOperations::new(self_signed_attestation(), [7u8; 32]);
+        let generated = operations
+            .generate_application_key(&AppId([1u8; 32]))
+            .unwrap();
+
+        assert!(operations
+            .unwrap_application_key(&AppId([2u8; 32]), &generated.handle)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn unwrap_with_other_seed_is_none() {
+        let application = AppId([1u8; 32]);
+        let generated = DerivedCryptoOperations::new(self_signed_attestation(), [7u8; 32])
+            .generate_application_key(&application)
+            .unwrap();
+
+        assert!(
+            DerivedCryptoOperations::new(self_signed_attestation(), [8u8; 32])
+                .unwrap_application_key(&application, &generated.handle)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
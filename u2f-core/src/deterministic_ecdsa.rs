@@ -0,0 +1,204 @@
+use openssl::bn::{BigNum, BigNumContext, BigNumRef};
+use openssl::ec::{EcKey, EcPoint};
+use openssl::error::ErrorStack;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+
+use crate::ecdsa_signature::EcdsaSignature;
+use crate::private_key::SecretBytes;
+
+/// ECDSA with the nonce derived from the key and message as in RFC 6979, so
+/// signing never depends on the quality of the system RNG and the same
+/// message always produces the same signature. `digest` hashes the message
+/// and, as the RFC asks, keys the HMAC the nonce is drawn from.
+pub(crate) fn sign(
+    key: &EcKey<Private>,
+    data: &[u8],
+    digest: MessageDigest,
+) -> Result<EcdsaSignature, ErrorStack> {
+    let group = key.group();
+    let mut ctx = BigNumContext::new()?;
+    let mut order = BigNum::new()?;
+    group.order(&mut order, &mut ctx)?;
+    let scalar_len = order.num_bytes();
+
+    let private_key = key.private_key();
+    let hash = bits2int(&hash(digest, data)?, &order)?;
+    let mut reduced_hash = BigNum::new()?;
+    reduced_hash.nnmod(&hash, &order, &mut ctx)?;
+
+    let mut nonces = NonceGenerator::new(
+        digest,
+        &SecretBytes(private_key.to_vec_padded(scalar_len)?),
+        &reduced_hash.to_vec_padded(scalar_len)?,
+    )?;
+    loop {
+        let k = nonces.next(&order)?;
+
+        let mut point = EcPoint::new(group)?;
+        point.mul_generator2(group, &k, &mut ctx)?;
+        let mut x = BigNum::new()?;
+        let mut y = BigNum::new()?;
+        point.affine_coordinates(group, &mut x, &mut y, &mut ctx)?;
+        let mut r = BigNum::new()?;
+        r.nnmod(&x, &order, &mut ctx)?;
+        if r.num_bits() == 0 {
+            continue;
+        }
+
+        // s = k^-1 (hash + r * private_key) mod order
+        let mut r_times_key = BigNum::new()?;
+        r_times_key.mod_mul(&r, private_key, &order, &mut ctx)?;
+        let mut sum = BigNum::new()?;
+        sum.mod_add(&reduced_hash, &r_times_key, &order, &mut ctx)?;
+        let mut k_inverse = BigNum::new()?;
+        k_inverse.mod_inverse(&k, &order, &mut ctx)?;
+        let mut s = BigNum::new()?;
+        s.mod_mul(&k_inverse, &sum, &order, &mut ctx)?;
+        if s.num_bits() == 0 {
+            continue;
+        }
+
+        return EcdsaSignature::from_components(&r, &s);
+    }
+}
+
+/// bits2int from RFC 6979 section 2.3.2, the leftmost bits of `bytes`, as
+/// many as the order has
+fn bits2int(bytes: &[u8], order: &BigNumRef) -> Result<BigNum, ErrorStack> {
+    let int = BigNum::from_slice(bytes)?;
+    let excess_bits = bytes.len() as i32 * 8 - order.num_bits();
+    if excess_bits <= 0 {
+        return Ok(int);
+    }
+    let mut truncated = BigNum::new()?;
+    truncated.rshift(&int, excess_bits)?;
+    Ok(truncated)
+}
+
+fn hmac(digest: MessageDigest, key: &[u8], data: &[&[u8]]) -> Result<Vec<u8>, ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(digest, &key)?;
+    for part in data {
+        signer.update(part)?;
+    }
+    signer.sign_to_vec()
+}
+
+/// The HMAC_DRBG based candidate generator from RFC 6979 section 3.2
+struct NonceGenerator {
+    digest: MessageDigest,
+    k: SecretBytes,
+    v: Vec<u8>,
+}
+
+impl NonceGenerator {
+    fn new(
+        digest: MessageDigest,
+        private_key: &[u8],
+        hash: &[u8],
+    ) -> Result<NonceGenerator, ErrorStack> {
+        let mut generator = NonceGenerator {
+            digest,
+            k: SecretBytes(vec![0u8; digest.size()]),
+            v: vec![1u8; digest.size()],
+        };
+        generator.reseed(0x00, &[private_key, hash])?;
+        generator.reseed(0x01, &[private_key, hash])?;
+        Ok(generator)
+    }
+
+    /// K = HMAC_K(V || separator || data), V = HMAC_K(V)
+    fn reseed(&mut self, separator: u8, data: &[&[u8]]) -> Result<(), ErrorStack> {
+        let separator = [separator];
+        let mut input: Vec<&[u8]> = vec![&self.v, &separator];
+        input.extend_from_slice(data);
+        self.k = SecretBytes(hmac(self.digest, &self.k, &input)?);
+        self.update_v()
+    }
+
+    fn update_v(&mut self) -> Result<(), ErrorStack> {
+        self.v = hmac(self.digest, &self.k, &[&self.v])?;
+        Ok(())
+    }
+
+    /// Returns the next candidate in [1, order), later candidates are only
+    /// needed if an earlier one produced a zero r or s.
+    fn next(&mut self, order: &BigNumRef) -> Result<BigNum, ErrorStack> {
+        loop {
+            let mut t = Vec::new();
+            while (t.len() as i32) * 8 < order.num_bits() {
+                self.update_v()?;
+                t.extend_from_slice(&self.v);
+            }
+            let candidate = bits2int(&t, order)?;
+            self.reseed(0x00, &[])?;
+            if candidate.num_bits() != 0 && candidate < *order {
+                return Ok(candidate);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::ec::EcGroup;
+    use openssl::ecdsa::EcdsaSig;
+    use openssl::nid::Nid;
+
+    use super::*;
+
+    /// Key from RFC 6979 appendix A.2.5
+    fn rfc6979_key() -> EcKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let mut ctx = BigNumContext::new().unwrap();
+        let private_number = BigNum::from_hex_str(
+            "C9AFA9D845BA75166B5C215767B1D6934E50C3DB36E89B127B8A622B120F6721",
+        )
+        .unwrap();
+        let mut public_point = EcPoint::new(&group).unwrap();
+        public_point
+            .mul_generator2(&group, &private_number, &mut ctx)
+            .unwrap();
+        EcKey::from_private_components(&group, &private_number, &public_point).unwrap()
+    }
+
+    #[test]
+    fn sign_matches_rfc6979_test_vector() {
+        let signature = sign(&rfc6979_key(), b"sample", MessageDigest::sha256()).unwrap();
+
+        assert_eq!(
+            signature.r().to_hex_str().unwrap().to_string(),
+            "EFD48B2AACB6A8FD1140DD9CD45E81D69D2C877B56AAF991C34D0EA84EAF3716"
+        );
+        assert_eq!(
+            signature.s().to_hex_str().unwrap().to_string(),
+            "F7CB1C942D657C41D436C7A1B6E29F65F3E900DBB9AFF4064DC4AB2F843ACDA8"
+        );
+    }
+
+    #[test]
+    fn sign_with_longer_digest_matches_rfc6979_test_vector() {
+        let signature = sign(&rfc6979_key(), b"sample", MessageDigest::sha384()).unwrap();
+
+        assert_eq!(
+            signature.r().to_hex_str().unwrap().to_string(),
+            "0EAFEA039B20E9B42309FB1D89E213057CBF973DC0CFC8F129EDDDC800EF7719"
+        );
+        assert_eq!(
+            signature.s().to_hex_str().unwrap().to_string(),
+            "4861F0491E6998B9455193E34E7B0D284DDD7149A74B95B9261F13ABDE940954"
+        );
+    }
+
+    #[test]
+    fn signature_verifies() {
+        let key = rfc6979_key();
+        let signature = sign(&key, b"test", MessageDigest::sha256()).unwrap();
+
+        let signature = EcdsaSig::from_der(signature.as_ref()).unwrap();
+        let digest = hash(MessageDigest::sha256(), b"test").unwrap();
+        assert!(signature.verify(&digest, &key).unwrap());
+    }
+}
@@ -0,0 +1,164 @@
+use openssl::bn::{BigNum, BigNumRef};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::serde_base64::{from_base64, to_base64};
+
+use super::Signature;
+
+/// ECDSA signature, ASN.1 DER encoded as required by U2F:
+/// SEQUENCE { r INTEGER, s INTEGER }
+#[derive(Clone, Debug)]
+pub struct EcdsaSignature(Vec<u8>);
+
+impl EcdsaSignature {
+    pub fn from_der(der: &[u8]) -> Result<EcdsaSignature, ErrorStack> {
+        EcdsaSig::from_der(der)?;
+        Ok(EcdsaSignature(der.to_vec()))
+    }
+
+    pub fn from_components(r: &BigNumRef, s: &BigNumRef) -> Result<EcdsaSignature, ErrorStack> {
+        let signature = EcdsaSig::from_private_components(r.to_owned()?, s.to_owned()?)?;
+        Ok(EcdsaSignature(signature.to_der()?))
+    }
+
+    /// Re-encodes the signature as minimal DER with `s` in the lower half of
+    /// the group `order`. `s` and `order - s` verify equally well, but some
+    /// relying parties only accept the low one.
+    pub fn canonicalize(&self, order: &BigNumRef) -> Result<EcdsaSignature, ErrorStack> {
+        let mut half_order = BigNum::new()?;
+        half_order.rshift1(order)?;
+        let mut s = self.s();
+        if s > half_order {
+            let mut low_s = BigNum::new()?;
+            low_s.checked_sub(order, &s)?;
+            s = low_s;
+        }
+        EcdsaSignature::from_components(&self.r(), &s)
+    }
+
+    pub fn r(&self) -> BigNum {
+        self.to_ecdsa_sig().r().to_owned().unwrap()
+    }
+
+    pub fn s(&self) -> BigNum {
+        self.to_ecdsa_sig().s().to_owned().unwrap()
+    }
+
+    pub fn to_der(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+
+    fn to_ecdsa_sig(&self) -> EcdsaSig {
+        // Validated as well-formed DER on construction
+        EcdsaSig::from_der(&self.0).unwrap()
+    }
+}
+
+impl Signature for EcdsaSignature {
+    fn box_clone(&self) -> Box<dyn Signature> {
+        Box::new(self.clone())
+    }
+}
+
+impl AsRef<[u8]> for EcdsaSignature {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl Serialize for dyn Signature {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        to_base64(&self, serializer)
+    }
+}
+
+/// Every signature this crate makes is ECDSA, so that is what a serialized
+/// signature is read back as.
+impl<'de> Deserialize<'de> for Box<dyn Signature> {
+    fn deserialize<D>(deserializer: D) -> Result<Box<dyn Signature>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let der = from_base64(deserializer)?;
+        let signature = EcdsaSignature::from_der(&der)
+            .map_err(|_| Error::custom("signature is not a DER encoded ECDSA signature"))?;
+        Ok(Box::new(signature))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::bn::BigNumContext;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::sha::sha256;
+
+    use super::*;
+    use crate::openssl_crypto::OpenSSLCryptoOperations;
+    use crate::self_signed_attestation::self_signed_attestation;
+    use crate::CryptoOperations;
+
+    #[test]
+    fn der_round_trips_through_components() {
+        let operations = OpenSSLCryptoOperations::new(self_signed_attestation());
+        let signature = operations.attest(b"message").unwrap();
+
+        let decoded = EcdsaSignature::from_der(signature.as_ref().as_ref()).unwrap();
+        let encoded = EcdsaSignature::from_components(&decoded.r(), &decoded.s()).unwrap();
+
+        assert_eq!(encoded.to_der(), signature.as_ref().as_ref());
+    }
+
+    #[test]
+    fn canonicalize_converts_high_s_to_low_s() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let mut order = BigNum::new().unwrap();
+        group
+            .order(&mut order, &mut BigNumContext::new().unwrap())
+            .unwrap();
+        let mut half_order = BigNum::new().unwrap();
+        half_order.rshift1(&order).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let digest = sha256(b"message");
+        let signature = EcdsaSig::sign(&digest, &key).unwrap();
+        // Take whichever of s and order - s is the high one
+        let mut high_s = BigNum::new().unwrap();
+        high_s.checked_sub(&order, signature.s()).unwrap();
+        if high_s < half_order {
+            high_s = signature.s().to_owned().unwrap();
+        }
+        let high = EcdsaSignature::from_components(signature.r(), &high_s).unwrap();
+
+        let canonical = high.canonicalize(&order).unwrap();
+
+        assert!(canonical.s() <= half_order);
+        assert_eq!(canonical.r(), high.r());
+        assert!(canonical.to_ecdsa_sig().verify(&digest, &key).unwrap());
+    }
+
+    #[test]
+    fn canonicalize_is_idempotent() {
+        let operations = OpenSSLCryptoOperations::new(self_signed_attestation());
+        let signature = operations.attest(b"message").unwrap();
+        let decoded = EcdsaSignature::from_der(signature.as_ref().as_ref()).unwrap();
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let mut order = BigNum::new().unwrap();
+        group
+            .order(&mut order, &mut BigNumContext::new().unwrap())
+            .unwrap();
+        let low = decoded.canonicalize(&order).unwrap();
+
+        assert_eq!(low.canonicalize(&order).unwrap().to_der(), low.to_der());
+    }
+
+    #[test]
+    fn from_der_with_malformed_input_errors() {
+        assert!(EcdsaSignature::from_der(&[0x30, 0x02, 0x01]).is_err());
+    }
+}
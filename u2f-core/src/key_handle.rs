@@ -3,10 +3,37 @@ use std::result::Result;
 
 use crate::constants::{DEFAULT_KEY_HANDLE_LEN, MAX_KEY_HANDLE_LEN};
 use rand::distributions::{Distribution, Standard};
-use rand::Rng;
+use rand::{Rng, RngCore};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use crate::serde_base64::{from_base64, to_base64};
 use subtle::ConstantTimeEq;
+use ring::digest;
+
+/// Number of digest bytes shown by the `Debug` output
+const FINGERPRINT_LEN: usize = 4;
+
+/// How a key handle leads back to its key, recorded in the first byte of
+/// every handle this crate generates.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyHandleVersion {
+    /// Random bytes naming a key kept in storage.
+    Random = 1,
+    /// The key itself, sealed with the device's key wrapping key.
+    Wrapped = 2,
+    /// A salt and tag the key is derived from with the device seed.
+    Derived = 3,
+}
+
+impl KeyHandleVersion {
+    fn from_byte(byte: u8) -> Option<KeyHandleVersion> {
+        match byte {
+            1 => Some(KeyHandleVersion::Random),
+            2 => Some(KeyHandleVersion::Wrapped),
+            3 => Some(KeyHandleVersion::Derived),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Clone, Eq, PartialEq)]
 pub struct KeyHandle(Vec<u8>);
@@ -17,6 +44,52 @@ impl KeyHandle {
         KeyHandle(bytes.to_vec())
     }
 
+    /// A random handle of the default length, or the error from an RNG
+    /// that could not produce any bytes.
+    pub(crate) fn random(rng: &mut dyn RngCore) -> Result<KeyHandle, rand::Error> {
+        let mut bytes = vec![0u8; DEFAULT_KEY_HANDLE_LEN];
+        bytes[0] = KeyHandleVersion::Random as u8;
+        rng.try_fill_bytes(&mut bytes[1..])?;
+        Ok(KeyHandle(bytes))
+    }
+
+    /// `body` prefixed with the tag for `version`.
+    pub(crate) fn tagged(version: KeyHandleVersion, body: &[u8]) -> KeyHandle {
+        let mut bytes = Vec::with_capacity(1 + body.len());
+        bytes.push(version as u8);
+        bytes.extend_from_slice(body);
+        KeyHandle::from(&bytes)
+    }
+
+    /// The version the first byte claims. Handles generated before the tag
+    /// was added have none, or a first byte that only looks like one, so
+    /// this is a hint of where to look first rather than a guarantee.
+    pub fn version(&self) -> Option<KeyHandleVersion> {
+        self.0
+            .first()
+            .cloned()
+            .and_then(KeyHandleVersion::from_byte)
+    }
+
+    /// The bytes after the tag, if the handle is tagged with `version`.
+    pub(crate) fn body(&self, version: KeyHandleVersion) -> Option<&[u8]> {
+        match self.0.split_first() {
+            Some((&tag, body)) if tag == version as u8 => Some(body),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Compares handles without returning early on the first differing byte,
+    /// so the time taken does not reveal how much of a guessed handle matched.
+    /// Prefer this over `==` whenever one side came from a request.
     pub fn eq_consttime(&self, other: &KeyHandle) -> bool {
         self.0.ct_eq(&other.0).unwrap_u8() == 1
     }
@@ -34,18 +107,24 @@ impl AsRef<[u8]> for KeyHandle {
 
 impl Distribution<KeyHandle> for Standard {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> KeyHandle {
-        KeyHandle(
-            Standard
-                .sample_iter(rng)
-                .take(DEFAULT_KEY_HANDLE_LEN)
-                .collect(),
-        )
+        let mut bytes = vec![0u8; DEFAULT_KEY_HANDLE_LEN];
+        bytes[0] = KeyHandleVersion::Random as u8;
+        rng.fill_bytes(&mut bytes[1..]);
+        KeyHandle(bytes)
     }
 }
 
+/// Only a short fingerprint of the digest is printed, enough to tell handles
+/// apart in logs without revealing the wrapped key material.
 impl Debug for KeyHandle {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "KeyHandle")
+        let digest = digest::digest(&digest::SHA256, &self.0);
+        write!(
+            f,
+            "KeyHandle(len={}, sha256={}..)",
+            self.0.len(),
+            hex::encode(&digest.as_ref()[..FINGERPRINT_LEN])
+        )
     }
 }
 
@@ -63,6 +142,70 @@ impl<'de> Deserialize<'de> for KeyHandle {
     where
         D: Deserializer<'de>,
     {
-        Ok(KeyHandle(from_base64(deserializer)?))
+        use serde::de::Error;
+        let bytes = from_base64(deserializer)?;
+        if bytes.len() > MAX_KEY_HANDLE_LEN {
+            return Err(Error::custom("key handle is too long"));
+        }
+        Ok(KeyHandle(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eq_consttime_matches_equal_handles() {
+        assert!(KeyHandle::from(&[1u8; 64]).eq_consttime(&KeyHandle::from(&[1u8; 64])));
+    }
+
+    #[test]
+    fn eq_consttime_rejects_different_handles() {
+        let mut last_byte_differs = [1u8; 64];
+        last_byte_differs[63] = 2;
+
+        assert!(!KeyHandle::from(&[1u8; 64]).eq_consttime(&KeyHandle::from(&last_byte_differs)));
+        assert!(!KeyHandle::from(&[1u8; 64]).eq_consttime(&KeyHandle::from(&[1u8; 32])));
+    }
+
+    #[test]
+    fn debug_does_not_contain_handle_bytes() {
+        let handle = KeyHandle::from(&[0xabu8; 64]);
+
+        let debug = format!("{:?}", handle);
+
+        assert!(debug.starts_with("KeyHandle(len=64, sha256="));
+        assert!(!debug.contains("abab"));
+        assert_ne!(debug, format!("{:?}", KeyHandle::from(&[0xcdu8; 64])));
+    }
+
+    #[test]
+    fn random_handles_are_tagged_random() {
+        let handle = KeyHandle::random(&mut rand::thread_rng()).unwrap();
+
+        assert_eq!(handle.len(), DEFAULT_KEY_HANDLE_LEN);
+        assert_eq!(handle.version(), Some(KeyHandleVersion::Random));
+        assert_eq!(
+            handle.body(KeyHandleVersion::Random).unwrap().len(),
+            DEFAULT_KEY_HANDLE_LEN - 1
+        );
+    }
+
+    #[test]
+    fn body_strips_only_a_matching_tag() {
+        let handle = KeyHandle::tagged(KeyHandleVersion::Wrapped, &[7u8; 4]);
+
+        assert_eq!(handle.as_ref(), &[2, 7, 7, 7, 7]);
+        assert_eq!(handle.body(KeyHandleVersion::Wrapped), Some(&[7u8; 4][..]));
+        assert_eq!(handle.body(KeyHandleVersion::Random), None);
+        assert_eq!(KeyHandle::from(&[0u8; 4]).version(), None);
+        assert_eq!(KeyHandle::from(&[]).version(), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn from_rejects_handles_longer_than_255_bytes() {
+        KeyHandle::from(&[0u8; 256]);
     }
 }
@@ -0,0 +1,189 @@
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey, EcPoint};
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+use crate::app_id::AppId;
+use crate::key_handle::{KeyHandle, KeyHandleVersion};
+use crate::private_key::{PrivateKey, SecretBytes};
+
+const WRAPPING_KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const PRIVATE_KEY_LEN: usize = 32;
+
+/// Device secret used to seal application private keys into their key handles.
+///
+/// A wrapped key handle is laid out as `version || nonce || ciphertext || tag`,
+/// where the ciphertext is the AES-256-GCM encryption of the private scalar
+/// and the application parameter is bound in as additional authenticated
+/// data. Only this device can recover the key, and only for the application
+/// it was registered with.
+pub struct KeyWrappingKey([u8; WRAPPING_KEY_LEN]);
+
+impl KeyWrappingKey {
+    pub fn from_bytes(bytes: [u8; WRAPPING_KEY_LEN]) -> KeyWrappingKey {
+        KeyWrappingKey(bytes)
+    }
+
+    /// A new key from the OpenSSL RNG, or its error if it has no entropy.
+    pub fn generate() -> Result<KeyWrappingKey, ErrorStack> {
+        let mut bytes = [0u8; WRAPPING_KEY_LEN];
+        rand_bytes(&mut bytes)?;
+        Ok(KeyWrappingKey(bytes))
+    }
+
+    pub(crate) fn wrap(
+        &self,
+        application: &AppId,
+        key: &PrivateKey,
+    ) -> Result<KeyHandle, ErrorStack> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand_bytes(&mut nonce)?;
+        let private_key = SecretBytes(key.0.private_key().to_vec_padded(PRIVATE_KEY_LEN as i32)?);
+
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.0,
+            Some(&nonce),
+            application.as_ref(),
+            &private_key,
+            &mut tag,
+        )?;
+
+        let mut bytes = Vec::with_capacity(NONCE_LEN + ciphertext.len() + TAG_LEN);
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&ciphertext);
+        bytes.extend_from_slice(&tag);
+        Ok(KeyHandle::tagged(KeyHandleVersion::Wrapped, &bytes))
+    }
+
+    /// Returns `None` if the handle was not wrapped by this device for the given application.
+    pub(crate) fn unwrap(&self, application: &AppId, handle: &KeyHandle) -> Option<PrivateKey> {
+        // Handles wrapped before the version byte was added have no tag
+        let bytes = match handle.body(KeyHandleVersion::Wrapped) {
+            Some(body) if body.len() == NONCE_LEN + PRIVATE_KEY_LEN + TAG_LEN => body,
+            _ => handle.as_ref(),
+        };
+        if bytes.len() != NONCE_LEN + PRIVATE_KEY_LEN + TAG_LEN {
+            return None;
+        }
+        let (nonce, rest) = bytes.split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(PRIVATE_KEY_LEN);
+
+        let private_key = SecretBytes(
+            decrypt_aead(
+                Cipher::aes_256_gcm(),
+                &self.0,
+                Some(nonce),
+                application.as_ref(),
+                ciphertext,
+                tag,
+            )
+            .ok()?,
+        );
+        key_from_private_bytes(&private_key).ok()
+    }
+}
+
+fn key_from_private_bytes(bytes: &[u8]) -> Result<PrivateKey, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let mut ctx = BigNumContext::new()?;
+    let private_number = BigNum::from_slice(bytes)?;
+    let mut public_point = EcPoint::new(&group)?;
+    public_point.mul_generator2(&group, &private_number, &mut ctx)?;
+    let ec_key = EcKey::from_private_components(&group, &private_number, &public_point)?;
+    ec_key.check_key()?;
+    Ok(PrivateKey(ec_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::ec::EcKey;
+
+    use super::*;
+
+    fn generate_key() -> PrivateKey {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PrivateKey(EcKey::generate(&group).unwrap())
+    }
+
+    #[test]
+    fn unwrap_round_trips_private_key() {
+        let wrapping_key = KeyWrappingKey::generate().unwrap();
+        let application = AppId([1u8; 32]);
+        let key = generate_key();
+
+        let handle = wrapping_key.wrap(&application, &key).unwrap();
+        let unwrapped = wrapping_key.unwrap(&application, &handle).unwrap();
+
+        assert_eq!(
+            unwrapped.0.private_key_to_der().unwrap(),
+            key.0.private_key_to_der().unwrap()
+        );
+    }
+
+    #[test]
+    fn unwrap_with_different_application_is_none() {
+        let wrapping_key = KeyWrappingKey::generate().unwrap();
+        let key = generate_key();
+
+        let handle = wrapping_key.wrap(&AppId([1u8; 32]), &key).unwrap();
+
+        assert!(wrapping_key.unwrap(&AppId([2u8; 32]), &handle).is_none());
+    }
+
+    #[test]
+    fn unwrap_with_different_wrapping_key_is_none() {
+        let application = AppId([1u8; 32]);
+        let key = generate_key();
+
+        let handle = KeyWrappingKey::generate()
+            .unwrap()
+            .wrap(&application, &key)
+            .unwrap();
+
+        assert!(KeyWrappingKey::generate()
+            .unwrap()
+            .unwrap(&application, &handle)
+            .is_none());
+    }
+
+    #[test]
+    fn wrapped_handles_are_tagged_wrapped() {
+        let handle = KeyWrappingKey::generate()
+            .unwrap()
+            .wrap(&AppId([1u8; 32]), &generate_key())
+            .unwrap();
+
+        assert_eq!(handle.version(), Some(KeyHandleVersion::Wrapped));
+        assert_eq!(handle.len(), 1 + NONCE_LEN + PRIVATE_KEY_LEN + TAG_LEN);
+    }
+
+    #[test]
+    fn unwrap_accepts_handles_from_before_the_tag() {
+        let wrapping_key = KeyWrappingKey::generate().unwrap();
+        let application = AppId([1u8; 32]);
+        let key = generate_key();
+
+        let handle = wrapping_key.wrap(&application, &key).unwrap();
+        let untagged = KeyHandle::from(handle.body(KeyHandleVersion::Wrapped).unwrap());
+        let unwrapped = wrapping_key.unwrap(&application, &untagged).unwrap();
+
+        assert_eq!(
+            unwrapped.0.private_key_to_der().unwrap(),
+            key.0.private_key_to_der().unwrap()
+        );
+    }
+
+    #[test]
+    fn unwrap_with_random_handle_is_none() {
+        let wrapping_key = KeyWrappingKey::generate().unwrap();
+        let handle: KeyHandle = rand::random();
+
+        assert!(wrapping_key.unwrap(&AppId([1u8; 32]), &handle).is_none());
+    }
+}
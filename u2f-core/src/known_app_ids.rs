@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 
 use crate::app_id::AppId;
-use ring::digest;
 
 // Known bogus app id hash, Chrome does a bogus register command after certain authentication failures,
 // this "force[s] the user to tap the [key] before revealing [the authentication state to the site]"
@@ -48,5 +47,5 @@ lazy_static! {
 }
 
 fn from_url(url: &str) -> AppId {
-    AppId::from_bytes(digest::digest(&digest::SHA256, url.as_bytes()).as_ref())
+    AppId::from_app_id(url)
 }
@@ -0,0 +1,50 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Running totals of requests handled, cheap enough to bump on every request.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    registrations: AtomicU64,
+    authentications: AtomicU64,
+    invalid_key_handles: AtomicU64,
+    approvals_denied: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn registered(&self) {
+        self.registrations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn authenticated(&self) {
+        self.authentications.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn invalid_key_handle(&self) {
+        self.invalid_key_handles.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn approval_denied(&self) {
+        self.approvals_denied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            registrations: self.registrations.load(Ordering::Relaxed),
+            authentications: self.authentications.load(Ordering::Relaxed),
+            invalid_key_handles: self.invalid_key_handles.load(Ordering::Relaxed),
+            approvals_denied: self.approvals_denied.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Totals since the `U2F` was created, see `U2F::metrics`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Registrations that produced a response
+    pub registrations: u64,
+    /// Authentications that produced a signature, with or without user presence
+    pub authentications: u64,
+    /// Authentications refused because the key handle was not issued for the application
+    pub invalid_key_handles: u64,
+    /// Registrations and authentications the user did not approve
+    pub approvals_denied: u64,
+}
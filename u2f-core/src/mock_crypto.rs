@@ -0,0 +1,142 @@
+//! A `CryptoOperations` for tests that never touches the system RNG.
+//!
+//! Application keys and key handles are derived from how many keys were
+//! generated before, and signatures use RFC 6979 nonces, so two instances
+//! given the same calls produce byte for byte identical responses. The keys
+//! are real P-256 keys, signatures verify like any other.
+
+use std::cell::Cell;
+
+use byteorder::{BigEndian, WriteBytesExt};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey, EcPoint};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use ring::digest;
+
+use crate::app_id::AppId;
+use crate::application_key::ApplicationKey;
+use crate::attestation::{Attestation, AttestationCertificate};
+use crate::deterministic_ecdsa;
+use crate::key_handle::{KeyHandle, KeyHandleVersion};
+use crate::private_key::PrivateKey;
+
+use super::CryptoOperations;
+use super::KeyError;
+use super::SignError;
+use super::Signature;
+
+pub(crate) struct MockCryptoOperations {
+    attestation: Attestation,
+    generated_keys: Cell<u32>,
+}
+
+impl MockCryptoOperations {
+    pub(crate) fn new(attestation: Attestation) -> MockCryptoOperations {
+        MockCryptoOperations {
+            attestation,
+            generated_keys: Cell::new(0),
+        }
+    }
+
+    fn derive(label: &[u8], index: u32) -> digest::Digest {
+        let mut input = label.to_vec();
+        input.write_u32::<BigEndian>(index).unwrap();
+        digest::digest(&digest::SHA256, &input)
+    }
+
+    fn derive_key(index: u32) -> Result<PrivateKey, ErrorStack> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let mut ctx = BigNumContext::new()?;
+        let mut order = BigNum::new()?;
+        group.order(&mut order, &mut ctx)?;
+
+        let seed = BigNum::from_slice(Self::derive(b"mock application key", index).as_ref())?;
+        let mut private_number = BigNum::new()?;
+        private_number.nnmod(&seed, &order, &mut ctx)?;
+        let mut public_point = EcPoint::new(&group)?;
+        public_point.mul_generator2(&group, &private_number, &mut ctx)?;
+        let ec_key = EcKey::from_private_components(&group, &private_number, &public_point)?;
+        Ok(PrivateKey(ec_key))
+    }
+}
+
+impl CryptoOperations for MockCryptoOperations {
+    fn attest(&self, data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
+        self.sign(&self.attestation.key, data)
+    }
+
+    fn generate_application_key(&self, application: &AppId) -> Result<ApplicationKey, KeyError> {
+        let application_key = self.preview_application_key(application)?;
+        self.generated_keys.set(self.generated_keys.get() + 1);
+        Ok(application_key)
+    }
+
+    /// The key the next call to `generate_application_key` will return.
+    fn preview_application_key(&self, application: &AppId) -> Result<ApplicationKey, KeyError> {
+        let index = self.generated_keys.get();
+        let key = Self::derive_key(index).map_err(KeyError::Generate)?;
+        let handle = KeyHandle::tagged(
+            KeyHandleVersion::Random,
+            Self::derive(b"mock key handle", index).as_ref(),
+        );
+        Ok(ApplicationKey::new(*application, handle, key))
+    }
+
+    fn get_attestation_certificate(&self) -> AttestationCertificate {
+        self.attestation.certificate.clone()
+    }
+
+    fn unwrap_application_key(
+        &self,
+        _: &AppId,
+        _: &KeyHandle,
+    ) -> Result<Option<ApplicationKey>, KeyError> {
+        Ok(None)
+    }
+
+    fn sign(&self, key: &PrivateKey, data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
+        let signature = deterministic_ecdsa::sign(&key.0, data, MessageDigest::sha256())
+            .map_err(SignError::Finalize)?;
+        Ok(Box::new(signature))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::self_signed_attestation::self_signed_attestation;
+
+    use super::*;
+
+    #[test]
+    fn generated_keys_are_repeatable() {
+        let application = AppId([0u8; 32]);
+        let first = MockCryptoOperations::new(self_signed_attestation());
+        let second = MockCryptoOperations::new(self_signed_attestation());
+
+        let first_key = first.generate_application_key(&application).unwrap();
+        let second_key = second.generate_application_key(&application).unwrap();
+
+        assert_eq!(first_key.handle, second_key.handle);
+        assert_eq!(
+            first_key.key().0.private_key(),
+            second_key.key().0.private_key()
+        );
+    }
+
+    #[test]
+    fn generated_keys_are_distinct() {
+        let application = AppId([0u8; 32]);
+        let operations = MockCryptoOperations::new(self_signed_attestation());
+
+        let first_key = operations.generate_application_key(&application).unwrap();
+        let second_key = operations.generate_application_key(&application).unwrap();
+
+        assert_ne!(first_key.handle, second_key.handle);
+        assert_ne!(
+            first_key.key().0.private_key(),
+            second_key.key().0.private_key()
+        );
+    }
+}
@@ -1,74 +1,453 @@
 use std::io;
+use std::sync::{Mutex, MutexGuard};
 
 use crate::app_id::AppId;
 use crate::application_key::ApplicationKey;
-use crate::attestation::{Attestation, AttestationCertificate};
+use crate::attestation::{Attestation, AttestationCertificate, LoadAttestationError};
+use crate::deterministic_ecdsa;
+use crate::ecdsa_signature::EcdsaSignature;
 use crate::key_handle::KeyHandle;
+use crate::key_wrapping::KeyWrappingKey;
+use openssl::bn::{BigNum, BigNumContext};
 use openssl::ec::{EcGroup, EcKey};
 use openssl::hash::MessageDigest;
 use openssl::nid::Nid;
 use openssl::pkey::PKey;
 use openssl::sign::Signer;
 use crate::private_key::PrivateKey;
+use crate::public_key::verify_signature_with_digest;
+use rand::rngs::OsRng;
+use rand::RngCore;
 
 use super::CryptoOperations;
+use super::KeyError;
 use super::SignError;
 use super::Signature;
 
 pub struct OpenSSLCryptoOperations {
+    aaguid: [u8; 16],
     attestation: Attestation,
+    key_wrapping_key: Option<Mutex<KeyWrappingKey>>,
+    deterministic_signatures: bool,
+    low_s_signatures: bool,
+    curve: Nid,
+    digest: MessageDigest,
+    rng: Mutex<Box<dyn RngCore + Send>>,
 }
 
 impl OpenSSLCryptoOperations {
     pub fn new(attestation: Attestation) -> OpenSSLCryptoOperations {
         OpenSSLCryptoOperations {
-            attestation: attestation,
+            aaguid: [0u8; 16],
+            attestation,
+            key_wrapping_key: None,
+            deterministic_signatures: false,
+            low_s_signatures: false,
+            curve: Nid::X9_62_PRIME256V1,
+            digest: MessageDigest::sha256(),
+            rng: Mutex::new(Box::new(OsRng)),
         }
     }
 
-    fn generate_key() -> PrivateKey {
-        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
-        let ec_key = EcKey::generate(&group).unwrap();
-        PrivateKey(ec_key)
+    /// Attest with an existing certificate and key, see `Attestation::from_pem`.
+    pub fn load_attestation(
+        certificate_pem: &[u8],
+        key_pem: &[u8],
+    ) -> Result<OpenSSLCryptoOperations, LoadAttestationError> {
+        let attestation = Attestation::from_pem(certificate_pem, key_pem)?;
+        Ok(OpenSSLCryptoOperations::new(attestation))
+    }
+
+    /// Seal each generated application key into its key handle instead of
+    /// using a random handle, so the key can be recovered from the handle alone.
+    /// `U2F` then keeps no entry for it, it counts with the store's global
+    /// counter.
+    pub fn with_key_wrapping(
+        mut self,
+        key_wrapping_key: KeyWrappingKey,
+    ) -> OpenSSLCryptoOperations {
+        self.key_wrapping_key = Some(Mutex::new(key_wrapping_key));
+        self
+    }
+
+    /// Derive signature nonces from the key and message (RFC 6979) instead of
+    /// the RNG, signing the same data twice then gives identical signatures.
+    pub fn with_deterministic_signatures(mut self) -> OpenSSLCryptoOperations {
+        self.deterministic_signatures = true;
+        self
+    }
+
+    /// Canonicalize every signature to low-S form, see
+    /// `EcdsaSignature::canonicalize`.
+    pub fn with_low_s_signatures(mut self) -> OpenSSLCryptoOperations {
+        self.low_s_signatures = true;
+        self
+    }
+
+    /// Generate application keys on another curve than P-256. U2F relying
+    /// parties only understand P-256 and key wrapping only supports it, so
+    /// this is meant for FIDO2 credentials: `U2F::register` refuses other
+    /// curves, `make_credential` accepts P-384 with SHA-384 and P-521 with
+    /// SHA-512. Attestation always uses P-256.
+    pub fn with_curve(mut self, curve: Nid) -> OpenSSLCryptoOperations {
+        self.curve = curve;
+        self
+    }
+
+    /// Hash signed data with `digest` instead of SHA-256. U2F relying parties
+    /// only verify SHA-256 signatures, so this is for experimenting with
+    /// other curves.
+    pub fn with_digest(mut self, digest: MessageDigest) -> OpenSSLCryptoOperations {
+        self.digest = digest;
+        self
     }
 
-    fn generate_key_handle() -> io::Result<KeyHandle> {
-        Ok(rand::random())
+    /// Checks a signature made by `sign` against the raw public key, hashing
+    /// with the same digest. See `verify_signature` for the SHA-256 only check
+    /// relying parties make.
+    pub fn verify(&self, public_key: &[u8], data: &[u8], signature: &[u8]) -> io::Result<bool> {
+        verify_signature_with_digest(public_key, data, signature, self.digest)
     }
+
+    /// Report `aaguid` as the authenticator model in FIDO2 credentials. It
+    /// stays all zero otherwise, as befits a software token with no model.
+    pub fn with_aaguid(mut self, aaguid: [u8; 16]) -> OpenSSLCryptoOperations {
+        self.aaguid = aaguid;
+        self
+    }
+
+    /// Draw random key handles from `rng` instead of the operating system,
+    /// only useful to make tests deterministic.
+    pub fn with_rng<R: RngCore + Send + 'static>(mut self, rng: R) -> OpenSSLCryptoOperations {
+        self.rng = Mutex::new(Box::new(rng));
+        self
+    }
+
+    fn generate_key(&self) -> Result<PrivateKey, KeyError> {
+        let group = EcGroup::from_curve_name(self.curve).map_err(KeyError::Generate)?;
+        let ec_key = EcKey::generate(&group).map_err(KeyError::Generate)?;
+        Ok(PrivateKey(ec_key))
+    }
+
+    fn application_key(
+        &self,
+        application: &AppId,
+        rng: &mut dyn RngCore,
+    ) -> Result<ApplicationKey, KeyError> {
+        let key = self.generate_key()?;
+        let handle = match self.key_wrapping_key {
+            Some(ref key_wrapping_key) => lock(key_wrapping_key)
+                .wrap(application, &key)
+                .map_err(KeyError::Wrap)?,
+            None => KeyHandle::random(rng).map_err(KeyError::Random)?,
+        };
+        Ok(ApplicationKey::new(*application, handle, key))
+    }
+}
+
+/// Locks `mutex`, ignoring poisoning. Nothing guarded here is left half
+/// updated by a panic, so it stays as usable as before.
+pub(crate) fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
 }
 
 impl CryptoOperations for OpenSSLCryptoOperations {
+    fn aaguid(&self) -> [u8; 16] {
+        self.aaguid
+    }
+
+    fn curve(&self) -> Nid {
+        self.curve
+    }
+
+    fn digest(&self) -> MessageDigest {
+        self.digest
+    }
+
     fn attest(&self, data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
         self.sign(&self.attestation.key, data)
     }
 
-    fn generate_application_key(&self, application: &AppId) -> io::Result<ApplicationKey> {
-        let key = Self::generate_key();
-        let handle = Self::generate_key_handle()?;
+    fn generate_application_key(&self, application: &AppId) -> Result<ApplicationKey, KeyError> {
+        let mut rng = lock(&self.rng);
+        self.application_key(application, &mut **rng)
+    }
+
+    /// Draws the key handle from the operating system, so a seeded RNG hands
+    /// out the same handles whether or not there were previews in between.
+    /// With key wrapping the key is sealed under a wrapping key that is
+    /// dropped right away, so the handle never unwraps.
+    fn preview_application_key(&self, application: &AppId) -> Result<ApplicationKey, KeyError> {
+        if self.key_wrapping_key.is_none() {
+            return self.application_key(application, &mut OsRng);
+        }
+        let key = self.generate_key()?;
+        let handle = KeyWrappingKey::generate()
+            .and_then(|throwaway| throwaway.wrap(application, &key))
+            .map_err(KeyError::Wrap)?;
         Ok(ApplicationKey::new(*application, handle, key))
     }
 
+    fn unwrap_application_key(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> Result<Option<ApplicationKey>, KeyError> {
+        Ok(self.key_wrapping_key.as_ref().and_then(|key_wrapping_key| {
+            lock(key_wrapping_key)
+                .unwrap(application, handle)
+                .map(|key| ApplicationKey::new(*application, handle.clone(), key))
+        }))
+    }
+
+    /// Replaces the key wrapping key with a new random one. The new key only
+    /// exists in memory, whoever supplied the old one has no way to restore
+    /// the handles it wrapped.
+    fn reset(&self) -> Result<(), KeyError> {
+        if let Some(ref key_wrapping_key) = self.key_wrapping_key {
+            *lock(key_wrapping_key) = KeyWrappingKey::generate().map_err(KeyError::Rotate)?;
+        }
+        Ok(())
+    }
+
     fn get_attestation_certificate(&self) -> AttestationCertificate {
         self.attestation.certificate.clone()
     }
 
     fn sign(&self, key: &PrivateKey, data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
-        let ec_key = key.0.to_owned();
-        let pkey = PKey::from_ec_key(ec_key).unwrap();
-        let mut signer = Signer::new(MessageDigest::sha256(), &pkey).unwrap();
-        signer.update(data).unwrap();
-        let signature = signer.sign_to_vec().unwrap();
-        Ok(Box::new(RawSignature(signature)))
+        let signature = if self.deterministic_signatures {
+            deterministic_ecdsa::sign(&key.0, data, self.digest).map_err(SignError::Finalize)?
+        } else {
+            let ec_key = key.0.to_owned();
+            let pkey = PKey::from_ec_key(ec_key).map_err(SignError::KeyConversion)?;
+            let mut signer = Signer::new(self.digest, &pkey).map_err(SignError::SignerInit)?;
+            signer.update(data).map_err(SignError::Update)?;
+            let signature = signer.sign_to_vec().map_err(SignError::Finalize)?;
+            EcdsaSignature::from_der(&signature).map_err(SignError::Finalize)?
+        };
+
+        if !self.low_s_signatures {
+            return Ok(Box::new(signature));
+        }
+        let mut ctx = BigNumContext::new().map_err(SignError::Finalize)?;
+        let mut order = BigNum::new().map_err(SignError::Finalize)?;
+        key.0
+            .group()
+            .order(&mut order, &mut ctx)
+            .map_err(SignError::Finalize)?;
+        let signature = signature
+            .canonicalize(&order)
+            .map_err(SignError::Finalize)?;
+        Ok(Box::new(signature))
     }
 }
 
-#[derive(Debug)]
-struct RawSignature(Vec<u8>);
+#[cfg(test)]
+mod tests {
+    use openssl::ec::EcPoint;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::public_key::{verify_signature, PublicKey};
+    use crate::self_signed_attestation::self_signed_attestation;
+
+    use super::*;
+
+    #[test]
+    fn sign_with_invalid_key_errors() {
+        let operations = OpenSSLCryptoOperations::new(self_signed_attestation());
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        // A private scalar far larger than the group order is rejected by the signer
+        let private_number = BigNum::from_hex_str(&"F".repeat(100)).unwrap();
+        let public_point = EcPoint::new(&group).unwrap();
+        let key = PrivateKey(
+            EcKey::from_private_components(&group, &private_number, &public_point).unwrap(),
+        );
+
+        assert_matches!(operations.sign(&key, &[]), Err(SignError::SignerInit(_)));
+    }
+
+    #[test]
+    fn generate_application_key_uses_configured_curve() {
+        let operations =
+            OpenSSLCryptoOperations::new(self_signed_attestation()).with_curve(Nid::SECP384R1);
+
+        let application_key = operations
+            .generate_application_key(&AppId([0u8; 32]))
+            .unwrap();
+
+        assert_eq!(
+            application_key.key().0.group().curve_name(),
+            Some(Nid::SECP384R1)
+        );
+    }
+
+    #[test]
+    fn generate_application_key_defaults_to_p256() {
+        let operations = OpenSSLCryptoOperations::new(self_signed_attestation());
+
+        let application_key = operations
+            .generate_application_key(&AppId([0u8; 32]))
+            .unwrap();
+
+        assert_eq!(
+            application_key.key().0.group().curve_name(),
+            Some(Nid::X9_62_PRIME256V1)
+        );
+    }
+
+    #[test]
+    fn generate_application_key_with_seeded_rng_is_repeatable() {
+        let operations = OpenSSLCryptoOperations::new(self_signed_attestation())
+            .with_rng(StdRng::seed_from_u64(42));
+        let expected: KeyHandle = StdRng::seed_from_u64(42).gen();
+
+        let application_key = operations
+            .generate_application_key(&AppId([0u8; 32]))
+            .unwrap();
+
+        assert_eq!(application_key.handle, expected);
+    }
+
+    #[test]
+    fn operations_can_be_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<OpenSSLCryptoOperations>();
+    }
+
+    /// An RNG with no entropy source, like the OS RNG inside a sandbox
+    /// that forbids getrandom.
+    struct FailingRng;
+
+    impl RngCore for FailingRng {
+        fn next_u32(&mut self) -> u32 {
+            panic!("FailingRng has no entropy")
+        }
+        fn next_u64(&mut self) -> u64 {
+            panic!("FailingRng has no entropy")
+        }
+        fn fill_bytes(&mut self, _: &mut [u8]) {
+            panic!("FailingRng has no entropy")
+        }
+        fn try_fill_bytes(&mut self, _: &mut [u8]) -> Result<(), rand::Error> {
+            Err(rand::Error::new("no entropy"))
+        }
+    }
+
+    #[test]
+    fn generate_application_key_with_failing_rng_errors() {
+        let operations =
+            OpenSSLCryptoOperations::new(self_signed_attestation()).with_rng(FailingRng);
+
+        assert_matches!(
+            operations.generate_application_key(&AppId([0u8; 32])),
+            Err(KeyError::Random(_))
+        );
+    }
+
+    #[test]
+    fn deterministic_signatures_are_repeatable() {
+        let operations =
+            OpenSSLCryptoOperations::new(self_signed_attestation()).with_deterministic_signatures();
+        let key = operations.generate_key().unwrap();
+
+        let first = operations.sign(&key, b"message").unwrap();
+        let second = operations.sign(&key, b"message").unwrap();
+
+        assert_eq!(first.as_ref().as_ref(), second.as_ref().as_ref());
+    }
+
+    #[test]
+    fn low_s_signatures_flip_high_s() {
+        let plain =
+            OpenSSLCryptoOperations::new(self_signed_attestation()).with_deterministic_signatures();
+        let low_s = OpenSSLCryptoOperations::new(self_signed_attestation())
+            .with_deterministic_signatures()
+            .with_low_s_signatures();
+        let key = plain.generate_key().unwrap();
+        let mut order = BigNum::new().unwrap();
+        key.0
+            .group()
+            .order(&mut order, &mut BigNumContext::new().unwrap())
+            .unwrap();
+        let mut half_order = BigNum::new().unwrap();
+        half_order.rshift1(&order).unwrap();
+        let signature = |operations: &OpenSSLCryptoOperations, message: &[u8]| {
+            let signature = operations.sign(&key, message).unwrap();
+            EcdsaSignature::from_der(signature.as_ref().as_ref()).unwrap()
+        };
+        // Deterministic nonces make the high-S message repeatable, about
+        // every other message has one
+        let message = (0u8..=255)
+            .map(|i| vec![i])
+            .find(|message| signature(&plain, message).s() > half_order)
+            .unwrap();
+
+        let high = signature(&plain, &message);
+        let low = signature(&low_s, &message);
+
+        assert!(low.s() <= half_order);
+        assert_eq!(low.r(), high.r());
+        let mut flipped = BigNum::new().unwrap();
+        flipped.checked_sub(&order, &high.s()).unwrap();
+        assert_eq!(low.s(), flipped);
+    }
+
+    #[test]
+    fn low_s_signatures_have_s_in_lower_half_of_order() {
+        let operations =
+            OpenSSLCryptoOperations::new(self_signed_attestation()).with_low_s_signatures();
+        let key = operations.generate_key().unwrap();
+        let mut order = BigNum::new().unwrap();
+        key.0
+            .group()
+            .order(&mut order, &mut BigNumContext::new().unwrap())
+            .unwrap();
+        let mut half_order = BigNum::new().unwrap();
+        half_order.rshift1(&order).unwrap();
+
+        for _ in 0..16 {
+            let signature = operations.sign(&key, b"message").unwrap();
+            let signature = EcdsaSignature::from_der(signature.as_ref().as_ref()).unwrap();
+            assert!(signature.s() <= half_order);
+        }
+    }
+
+    #[test]
+    fn signatures_with_configured_digest_verify() {
+        let operations = OpenSSLCryptoOperations::new(self_signed_attestation())
+            .with_digest(MessageDigest::sha384());
+        let application_key = operations
+            .generate_application_key(&AppId([0u8; 32]))
+            .unwrap();
+        let public_key = PublicKey::from_key(application_key.key()).to_raw();
+
+        let signature = operations.sign(application_key.key(), b"message").unwrap();
+
+        let signature = signature.as_ref().as_ref();
+        assert!(operations
+            .verify(&public_key, b"message", signature)
+            .unwrap());
+        assert!(!verify_signature(&public_key, b"message", signature).unwrap());
+    }
+
+    #[test]
+    fn deterministic_signatures_with_configured_digest_verify() {
+        let operations = OpenSSLCryptoOperations::new(self_signed_attestation())
+            .with_deterministic_signatures()
+            .with_digest(MessageDigest::sha384());
+        let application_key = operations
+            .generate_application_key(&AppId([0u8; 32]))
+            .unwrap();
+        let public_key = PublicKey::from_key(application_key.key()).to_raw();
 
-impl Signature for RawSignature {}
+        let signature = operations.sign(application_key.key(), b"message").unwrap();
 
-impl AsRef<[u8]> for RawSignature {
-    fn as_ref(&self) -> &[u8] {
-        self.0.as_ref()
+        assert!(operations
+            .verify(&public_key, b"message", signature.as_ref().as_ref())
+            .unwrap());
     }
 }
@@ -2,6 +2,9 @@ use openssl::bn::BigNumContext;
 use openssl::ec::{EcGroup, EcKey, EcPoint, PointConversionForm};
 use openssl::nid::Nid;
 use openssl::pkey::Public;
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::{hash, MessageDigest};
+use std::io;
 use std::result::Result;
 
 use crate::constants::EC_POINT_FORMAT_UNCOMPRESSED;
@@ -11,8 +14,7 @@ pub struct PublicKey(EcKey<Public>);
 
 impl PublicKey {
     pub(crate) fn from_key(key: &PrivateKey) -> PublicKey {
-        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
-        PublicKey(EcKey::from_public_key(&group, &key.0.public_key()).unwrap())
+        PublicKey(EcKey::from_public_key(key.0.group(), key.0.public_key()).unwrap())
     }
 
     /// Raw ANSI X9.62 formatted Elliptic Curve public key [SEC1].
@@ -27,7 +29,8 @@ impl PublicKey {
             return Err(String::from("Expected uncompressed point"));
         }
         let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
-        let point = EcPoint::from_bytes(&group, bytes, &mut ctx).unwrap();
+        let point = EcPoint::from_bytes(&group, bytes, &mut ctx)
+            .map_err(|_| String::from("Point is not on the curve"))?;
         Ok(PublicKey(EcKey::from_public_key(&group, &point).unwrap()))
     }
 
@@ -47,3 +50,78 @@ impl PublicKey {
             .unwrap()
     }
 }
+
+/// Checks an ECDSA signature as produced by registration or authentication
+/// against the raw user public key from the registration response. A
+/// signature that is not valid DER is reported as not matching.
+pub fn verify_signature(user_public_key: &[u8], data: &[u8], signature: &[u8]) -> io::Result<bool> {
+    verify_signature_with_digest(user_public_key, data, signature, MessageDigest::sha256())
+}
+
+pub(crate) fn verify_signature_with_digest(
+    user_public_key: &[u8],
+    data: &[u8],
+    signature: &[u8],
+    digest: MessageDigest,
+) -> io::Result<bool> {
+    let public_key = PublicKey::from_bytes(user_public_key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let signature = match EcdsaSig::from_der(signature) {
+        Ok(signature) => signature,
+        Err(_) => return Ok(false),
+    };
+    signature
+        .verify(&hash(digest, data)?, public_key.as_ec_key())
+        .map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::openssl_crypto::OpenSSLCryptoOperations;
+    use crate::self_signed_attestation::self_signed_attestation;
+    use crate::{AppId, CryptoOperations};
+
+    use super::*;
+
+    fn signed_message() -> (Vec<u8>, Vec<u8>) {
+        let operations = OpenSSLCryptoOperations::new(self_signed_attestation());
+        let application_key = operations
+            .generate_application_key(&AppId([0u8; 32]))
+            .unwrap();
+        let signature = operations.sign(application_key.key(), b"message").unwrap();
+        (
+            PublicKey::from_key(application_key.key()).to_raw(),
+            signature.as_ref().as_ref().to_vec(),
+        )
+    }
+
+    #[test]
+    fn verify_signature_accepts_genuine_signature() {
+        let (public_key, signature) = signed_message();
+
+        assert!(verify_signature(&public_key, b"message", &signature).unwrap());
+    }
+
+    #[test]
+    fn verify_signature_rejects_tampered_data() {
+        let (public_key, signature) = signed_message();
+
+        assert!(!verify_signature(&public_key, b"massage", &signature).unwrap());
+    }
+
+    #[test]
+    fn verify_signature_rejects_tampered_signature() {
+        let (public_key, mut signature) = signed_message();
+        let last = signature.len() - 1;
+        signature[last] ^= 0x01;
+
+        assert!(!verify_signature(&public_key, b"message", &signature).unwrap());
+    }
+
+    #[test]
+    fn verify_signature_with_invalid_public_key_errors() {
+        let (_, signature) = signed_message();
+
+        assert!(verify_signature(&[0u8; 65], b"message", &signature).is_err());
+    }
+}
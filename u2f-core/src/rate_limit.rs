@@ -0,0 +1,82 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::app_id::AppId;
+
+/// Allows at most `max_attempts` authentication attempts per application
+/// within any sliding `window`.
+pub(crate) struct RateLimiter {
+    max_attempts: usize,
+    window: Duration,
+    attempts: RefCell<HashMap<AppId, VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(max_attempts: usize, window: Duration) -> RateLimiter {
+        RateLimiter {
+            max_attempts,
+            window,
+            attempts: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Records an attempt made at `now`, unless it would exceed the limit.
+    /// Rejected attempts are not recorded, so a client that keeps retrying is
+    /// let through again once its earlier attempts leave the window.
+    pub(crate) fn try_acquire(&self, application: &AppId, now: Instant) -> bool {
+        let window = self.window;
+        let is_recent = |attempt: &Instant| now.duration_since(*attempt) < window;
+        let mut attempts = self.attempts.borrow_mut();
+        // Forget applications without recent attempts so the map stays small
+        attempts.retain(|_, times| times.back().is_some_and(is_recent));
+
+        let times = attempts.entry(*application).or_default();
+        while times.front().is_some_and(|attempt| !is_recent(attempt)) {
+            times.pop_front();
+        }
+        if times.len() >= self.max_attempts {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn rejects_attempts_over_limit() {
+        let limiter = RateLimiter::new(2, WINDOW);
+        let application = AppId([0u8; 32]);
+        let now = Instant::now();
+
+        assert!(limiter.try_acquire(&application, now));
+        assert!(limiter.try_acquire(&application, now));
+        assert!(!limiter.try_acquire(&application, now));
+    }
+
+    #[test]
+    fn allows_attempts_after_window() {
+        let limiter = RateLimiter::new(1, WINDOW);
+        let application = AppId([0u8; 32]);
+        let now = Instant::now();
+
+        assert!(limiter.try_acquire(&application, now));
+        assert!(!limiter.try_acquire(&application, now + WINDOW / 2));
+        assert!(limiter.try_acquire(&application, now + WINDOW));
+    }
+
+    #[test]
+    fn limits_each_application_separately() {
+        let limiter = RateLimiter::new(1, WINDOW);
+        let now = Instant::now();
+
+        assert!(limiter.try_acquire(&AppId([0u8; 32]), now));
+        assert!(limiter.try_acquire(&AppId([1u8; 32]), now));
+    }
+}
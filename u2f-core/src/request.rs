@@ -3,11 +3,12 @@ use std::io::Read;
 use std::result::Result;
 
 use crate::app_id::AppId;
-use byteorder::{BigEndian, ReadBytesExt};
+use byteorder::ReadBytesExt;
 use crate::constants::*;
 use crate::key_handle::KeyHandle;
 
 use super::Challenge;
+use super::StatusCode;
 
 #[derive(Debug)]
 pub enum AuthenticateControlCode {
@@ -32,115 +33,130 @@ pub enum Request {
     Wink,
 }
 
+/// How the lengths in a request APDU were encoded, which limits how much
+/// response data the request can ask for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LengthEncoding {
+    Short,
+    Extended,
+}
+
+impl LengthEncoding {
+    /// The largest maximum response length (Ne) this encoding can express,
+    /// not counting the status word.
+    pub fn max_response_len(self) -> usize {
+        match self {
+            LengthEncoding::Short => 256,
+            LengthEncoding::Extended => 65_536,
+        }
+    }
+}
+
+quick_error! {
+    #[derive(Debug, PartialEq)]
+    pub enum ApduError {
+        ClassNotSupported(class: u8) {
+            display("Class byte 0x{:02x} is not supported", class)
+        }
+        InstructionNotSupported(instruction: u8) {
+            display("Instruction 0x{:02x} is not supported", instruction)
+        }
+        UnknownControlCode(control_code: u8) {
+            display("Authenticate control code 0x{:02x} is unknown", control_code)
+        }
+        WrongLength {
+            display("Request length is invalid")
+        }
+        UnsupportedParameters(parameter1: u8, parameter2: u8) {
+            display("Parameters 0x{:02x} 0x{:02x} are not supported", parameter1, parameter2)
+        }
+    }
+}
+
+impl ApduError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ApduError::ClassNotSupported(_) => StatusCode::RequestClassNotSupported,
+            ApduError::InstructionNotSupported(_) => StatusCode::RequestInstructionNotSuppored,
+            // U2F only defines SW_WRONG_DATA for rejected request data
+            ApduError::UnknownControlCode(_) => StatusCode::InvalidKeyHandle,
+            ApduError::UnsupportedParameters(..) => StatusCode::IncorrectParameters,
+            ApduError::WrongLength => StatusCode::RequestLengthInvalid,
+        }
+    }
+}
+
 impl Request {
-    /// Only supports Extended Length Encoding
-    pub fn decode(data: &[u8]) -> Result<Request, ()> {
-        let mut reader = Cursor::new(data);
+    /// Supports both Short and Extended Length Encoding
+    pub fn decode(data: &[u8]) -> Result<Request, ApduError> {
+        Request::decode_with_encoding(data).map(|(request, _)| request)
+    }
+
+    /// Like `decode`, but also tells which length encoding the request used.
+    pub fn decode_with_encoding(data: &[u8]) -> Result<(Request, LengthEncoding), ApduError> {
+        if data.len() < 4 {
+            return Err(ApduError::WrongLength);
+        }
 
         // CLA: Reserved to be used by the underlying transport protocol
-        let _class_byte = reader.read_u8().unwrap();
-        // TODO check or error with RequestClassNotSupported
+        let class_byte = data[0];
+        if class_byte != 0 {
+            return Err(ApduError::ClassNotSupported(class_byte));
+        }
 
         // INS: U2F command code
-        let command_code = reader.read_u8().unwrap();
-        // TODO check or error with RequestInstructionNotSuppored
+        let command_code = data[1];
 
         // P1, P2: Parameter 1 and 2, defined by each command.
-        let parameter1 = reader.read_u8().unwrap();
-        let parameter2 = reader.read_u8().unwrap();
+        let parameter1 = data[2];
+        let parameter2 = data[3];
 
-        // Extended Length Encoding
-        // Always begins with a byte of value 0
-        let zero_byte = reader.read_u8().unwrap();
-        assert_eq!(zero_byte, 0);
-
-        // Nc: Length of the request-data, range 0..65 535
-        // Lc: Encoding of Nc as two bytes
-        // If Nc is 0, Lc is omitted (Caveat: Not all implementations respect this)
-        let remaining_len = data.len() - reader.position() as usize;
-        let request_data_len = match remaining_len {
-            2 => {
-                // Lc was omitted, there is no request data
-                0
-            }
-            _ => {
-                // Lc in big-endian order
-                reader.read_u16::<BigEndian>().unwrap() as usize
-            }
-        };
+        let (request_data, length_encoding) = request_data(&data[4..])?;
 
-        // Request-data
-        let mut request_data = vec![0u8; request_data_len];
-        reader.read_exact(&mut request_data[..]).unwrap();
-
-        // Ne: Maximum length of the response data, range 0..65 536
-        // Le: Encoding of Ne as two bytes
-        // If no response data are expected, Le may be omitted.
-        let remaining_len = data.len() - reader.position() as usize;
-        let _max_response_data_len = match remaining_len {
-            0 => {
-                // Lc was omitted, instruction is not expected to yield any response bytes
-                0
-            }
-            2 => {
-                // Encoded as: Le1 Le2
-                let mut value = reader.read_u16::<BigEndian>().unwrap() as usize;
-                // When Ne = 65 536, let Le1 = 0 and Le2 = 0.
-                if value == 0 {
-                    // The MSB is lost when encoding to two bytes, but
-                    // since Le can be omitted when there are no request data
-                    // bytes, we can unambigously assume 0 to mean 65 535
-                    value = 65535;
-                }
-                value
-            }
-            _ => return Err(()),
-        };
-
-        // TODO If the instruction is not expected to yield any response bytes, L e may be omitted. O
         let mut reader = Cursor::new(request_data);
         let request = match command_code {
             REGISTER_COMMAND_CODE => {
                 // The challenge parameter [32 bytes].
-                let mut challenge_parameter = [0u8; 32];
-                reader.read_exact(&mut challenge_parameter[..]).unwrap();
+                let challenge_parameter = read_parameter(&mut reader)?;
 
                 // The application parameter [32 bytes].
-                let mut application_parameter = [0u8; 32];
-                reader.read_exact(&mut application_parameter[..]).unwrap();
+                let application_parameter = read_parameter(&mut reader)?;
 
-                assert_eq!(reader.position() as usize, request_data_len);
+                if reader.position() as usize != request_data.len() {
+                    return Err(ApduError::WrongLength);
+                }
                 Request::Register {
                     application: AppId(application_parameter),
                     challenge: Challenge(challenge_parameter),
                 }
             }
             AUTHENTICATE_COMMAND_CODE => {
-                assert_eq!(parameter2, 0);
-
                 // Control byte (P1).
                 let control_code = match parameter1 {
                     AUTH_CHECK_ONLY => AuthenticateControlCode::CheckOnly,
                     AUTH_ENFORCE => AuthenticateControlCode::EnforceUserPresenceAndSign,
                     AUTH_DONT_ENFORCE => AuthenticateControlCode::DontEnforceUserPresenceAndSign,
-                    _ => panic!("Unknown control code"),
+                    _ => return Err(ApduError::UnknownControlCode(parameter1)),
                 };
 
                 // The challenge parameter [32 bytes].
-                let mut challenge_parameter = [0u8; 32];
-                reader.read_exact(&mut challenge_parameter[..]).unwrap();
+                let challenge_parameter = read_parameter(&mut reader)?;
 
                 // The application parameter [32 bytes].
-                let mut application_parameter = [0u8; 32];
-                reader.read_exact(&mut application_parameter[..]).unwrap();
+                let application_parameter = read_parameter(&mut reader)?;
 
                 // key handle length byte [1 byte]
-                let key_handle_len = reader.read_u8().unwrap();
+                let key_handle_len = reader.read_u8().map_err(|_| ApduError::WrongLength)?;
 
                 // key handle [length specified in previous field]
                 let mut key_handle_bytes = vec![0u8; key_handle_len as usize];
-                reader.read_exact(&mut key_handle_bytes[..]).unwrap();
+                reader
+                    .read_exact(&mut key_handle_bytes[..])
+                    .map_err(|_| ApduError::WrongLength)?;
 
+                if reader.position() as usize != request_data.len() {
+                    return Err(ApduError::WrongLength);
+                }
                 Request::Authenticate {
                     application: AppId(application_parameter),
                     challenge: Challenge(challenge_parameter),
@@ -149,13 +165,324 @@ impl Request {
                 }
             }
             VERSION_COMMAND_CODE => {
-                assert_eq!(parameter1, 0);
-                assert_eq!(parameter2, 0);
-                assert_eq!(request_data_len, 0);
+                if parameter1 != 0 || parameter2 != 0 {
+                    return Err(ApduError::UnsupportedParameters(parameter1, parameter2));
+                }
+                if !request_data.is_empty() {
+                    return Err(ApduError::WrongLength);
+                }
                 Request::GetVersion
             }
-            _ => panic!("Not implemented"),
+            _ => return Err(ApduError::InstructionNotSupported(command_code)),
         };
-        Ok(request)
+        Ok((request, length_encoding))
+    }
+}
+
+/// Splits the APDU body following the four header bytes into its request-data,
+/// validating the Lc and Le fields along the way. The exact maximum response
+/// length (Ne) is not needed, responses are sent in full as long as the
+/// length encoding allows it.
+fn request_data(body: &[u8]) -> Result<(&[u8], LengthEncoding), ApduError> {
+    match body.len() {
+        // Case 1: No request data, no response data expected
+        0 => Ok((&[], LengthEncoding::Short)),
+        // Case 2S: Le is a single byte
+        1 => Ok((&[], LengthEncoding::Short)),
+        // Extended Length Encoding
+        // Always begins with a byte of value 0
+        _ if body[0] == 0 => {
+            if body.len() == 3 {
+                // Case 2E: Le encoded as two bytes, no request data
+                return Ok((&[], LengthEncoding::Extended));
+            }
+            if body.len() < 3 {
+                return Err(ApduError::WrongLength);
+            }
+
+            // Nc: Length of the request-data, range 0..65 535
+            // Lc: Encoding of Nc as two bytes
+            // If Nc is 0, Lc is omitted (Caveat: Not all implementations respect this)
+            let request_data_len = ((body[1] as usize) << 8) | body[2] as usize;
+            let rest = &body[3..];
+            match rest.len().checked_sub(request_data_len) {
+                // Case 3E: no Le, or Case 4E: Le encoded as two bytes
+                Some(0) | Some(2) => Ok((&rest[..request_data_len], LengthEncoding::Extended)),
+                _ => Err(ApduError::WrongLength),
+            }
+        }
+        // Short Length Encoding
+        _ => {
+            // Nc: Length of the request-data, range 1..255
+            // Lc: Encoding of Nc as one byte
+            let request_data_len = body[0] as usize;
+            let rest = &body[1..];
+            match rest.len().checked_sub(request_data_len) {
+                // Case 3S: no Le, or Case 4S: Le encoded as one byte
+                Some(0) | Some(1) => Ok((&rest[..request_data_len], LengthEncoding::Short)),
+                _ => Err(ApduError::WrongLength),
+            }
+        }
+    }
+}
+
+fn read_parameter(reader: &mut Cursor<&[u8]>) -> Result<[u8; 32], ApduError> {
+    let mut parameter = [0u8; 32];
+    reader
+        .read_exact(&mut parameter[..])
+        .map_err(|_| ApduError::WrongLength)?;
+    Ok(parameter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extended_apdu(instruction: u8, parameter1: u8, request_data: &[u8]) -> Vec<u8> {
+        let mut apdu = vec![0x00, instruction, parameter1, 0x00];
+        apdu.push(0x00);
+        apdu.push((request_data.len() >> 8) as u8);
+        apdu.push(request_data.len() as u8);
+        apdu.extend_from_slice(request_data);
+        apdu.extend_from_slice(&[0x00, 0x00]);
+        apdu
+    }
+
+    fn short_apdu(instruction: u8, parameter1: u8, request_data: &[u8]) -> Vec<u8> {
+        let mut apdu = vec![0x00, instruction, parameter1, 0x00];
+        apdu.push(request_data.len() as u8);
+        apdu.extend_from_slice(request_data);
+        apdu.push(0x00);
+        apdu
+    }
+
+    fn authenticate_data(key_handle: &[u8]) -> Vec<u8> {
+        let mut data = vec![1u8; 32];
+        data.extend_from_slice(&[2u8; 32]);
+        data.push(key_handle.len() as u8);
+        data.extend_from_slice(key_handle);
+        data
+    }
+
+    #[test]
+    fn decode_register_extended() {
+        let mut data = vec![1u8; 32];
+        data.extend_from_slice(&[2u8; 32]);
+
+        match Request::decode(&extended_apdu(REGISTER_COMMAND_CODE, 0x03, &data)).unwrap() {
+            Request::Register {
+                application,
+                challenge,
+            } => {
+                assert_eq!(challenge.as_ref(), &[1u8; 32]);
+                assert_eq!(application.as_ref(), &[2u8; 32]);
+            }
+            request => panic!("Unexpected request {:?}", request),
+        }
+    }
+
+    #[test]
+    fn decode_register_short() {
+        let mut data = vec![1u8; 32];
+        data.extend_from_slice(&[2u8; 32]);
+
+        match Request::decode(&short_apdu(REGISTER_COMMAND_CODE, 0x00, &data)).unwrap() {
+            Request::Register { application, .. } => {
+                assert_eq!(application.as_ref(), &[2u8; 32])
+            }
+            request => panic!("Unexpected request {:?}", request),
+        }
+    }
+
+    #[test]
+    fn decode_authenticate() {
+        let apdu = extended_apdu(
+            AUTHENTICATE_COMMAND_CODE,
+            AUTH_ENFORCE,
+            &authenticate_data(&[3u8; 64]),
+        );
+
+        match Request::decode(&apdu).unwrap() {
+            Request::Authenticate {
+                application,
+                control_code: AuthenticateControlCode::EnforceUserPresenceAndSign,
+                key_handle,
+                ..
+            } => {
+                assert_eq!(application.as_ref(), &[2u8; 32]);
+                assert_eq!(key_handle.as_ref(), &[3u8; 64][..]);
+            }
+            request => panic!("Unexpected request {:?}", request),
+        }
+    }
+
+    #[test]
+    fn decode_authenticate_with_unknown_control_code_errors() {
+        let apdu = extended_apdu(
+            AUTHENTICATE_COMMAND_CODE,
+            0x42,
+            &authenticate_data(&[3u8; 8]),
+        );
+
+        assert_eq!(
+            Request::decode(&apdu).unwrap_err(),
+            ApduError::UnknownControlCode(0x42)
+        );
+    }
+
+    #[test]
+    fn decode_version() {
+        let short = [0x00, VERSION_COMMAND_CODE, 0x00, 0x00];
+        let extended = [0x00, VERSION_COMMAND_CODE, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+        match Request::decode(&short).unwrap() {
+            Request::GetVersion => {}
+            request => panic!("Unexpected request {:?}", request),
+        }
+        match Request::decode(&extended).unwrap() {
+            Request::GetVersion => {}
+            request => panic!("Unexpected request {:?}", request),
+        }
+    }
+
+    #[test]
+    fn decode_version_with_parameters_errors() {
+        assert_eq!(
+            Request::decode(&[0x00, VERSION_COMMAND_CODE, 0x01, 0x00]).unwrap_err(),
+            ApduError::UnsupportedParameters(0x01, 0x00)
+        );
+        assert_eq!(
+            Request::decode(&[0x00, VERSION_COMMAND_CODE, 0x00, 0x02]).unwrap_err(),
+            ApduError::UnsupportedParameters(0x00, 0x02)
+        );
+    }
+
+    #[test]
+    fn decode_reports_length_encoding() {
+        let data = [0u8; 64];
+
+        let (_, short) =
+            Request::decode_with_encoding(&short_apdu(REGISTER_COMMAND_CODE, 0x00, &data)).unwrap();
+        let (_, extended) =
+            Request::decode_with_encoding(&extended_apdu(REGISTER_COMMAND_CODE, 0x00, &data))
+                .unwrap();
+
+        assert_eq!(short, LengthEncoding::Short);
+        assert_eq!(extended, LengthEncoding::Extended);
+    }
+
+    #[test]
+    fn decode_unknown_instruction_errors() {
+        assert_eq!(
+            Request::decode(&[0x00, 0x42, 0x00, 0x00]).unwrap_err(),
+            ApduError::InstructionNotSupported(0x42)
+        );
+    }
+
+    #[test]
+    fn decode_unsupported_class_errors() {
+        assert_eq!(
+            Request::decode(&[0x80, VERSION_COMMAND_CODE, 0x00, 0x00]).unwrap_err(),
+            ApduError::ClassNotSupported(0x80)
+        );
+    }
+
+    #[test]
+    fn decode_truncated_header_errors() {
+        assert_eq!(
+            Request::decode(&[0x00, VERSION_COMMAND_CODE]).unwrap_err(),
+            ApduError::WrongLength
+        );
+    }
+
+    #[test]
+    fn decode_lc_longer_than_data_errors() {
+        let mut apdu = extended_apdu(REGISTER_COMMAND_CODE, 0x00, &[0u8; 64]);
+        apdu.truncate(apdu.len() - 10);
+
+        assert_eq!(Request::decode(&apdu).unwrap_err(), ApduError::WrongLength);
+    }
+
+    #[test]
+    fn decode_register_with_short_data_errors() {
+        let apdu = short_apdu(REGISTER_COMMAND_CODE, 0x00, &[0u8; 63]);
+
+        assert_eq!(Request::decode(&apdu).unwrap_err(), ApduError::WrongLength);
+    }
+
+    #[test]
+    fn decode_authenticate_with_truncated_key_handle_errors() {
+        let mut data = authenticate_data(&[3u8; 64]);
+        data.truncate(data.len() - 1);
+        let apdu = extended_apdu(AUTHENTICATE_COMMAND_CODE, AUTH_ENFORCE, &data);
+
+        assert_eq!(Request::decode(&apdu).unwrap_err(), ApduError::WrongLength);
+    }
+
+    #[test]
+    fn decode_empty_errors() {
+        assert_eq!(Request::decode(&[]).unwrap_err(), ApduError::WrongLength);
+    }
+
+    #[test]
+    fn decode_register_with_zero_length_body_errors() {
+        let header_only = [0x00, REGISTER_COMMAND_CODE, 0x00, 0x00];
+        let zero_lc = extended_apdu(REGISTER_COMMAND_CODE, 0x00, &[]);
+
+        assert_eq!(
+            Request::decode(&header_only).unwrap_err(),
+            ApduError::WrongLength
+        );
+        assert_eq!(
+            Request::decode(&zero_lc).unwrap_err(),
+            ApduError::WrongLength
+        );
+    }
+
+    #[test]
+    fn decode_short_lc_longer_than_data_errors() {
+        let apdu = [0x00, REGISTER_COMMAND_CODE, 0x00, 0x00, 0x40, 1, 2, 3];
+
+        assert_eq!(Request::decode(&apdu).unwrap_err(), ApduError::WrongLength);
+    }
+
+    #[test]
+    fn decode_truncated_requests_errors() {
+        let apdus = vec![
+            short_apdu(REGISTER_COMMAND_CODE, 0x00, &[0u8; 64]),
+            extended_apdu(REGISTER_COMMAND_CODE, 0x00, &[0u8; 64]),
+            extended_apdu(
+                AUTHENTICATE_COMMAND_CODE,
+                AUTH_ENFORCE,
+                &authenticate_data(&[3u8; 64]),
+            ),
+        ];
+
+        for apdu in apdus {
+            // Dropping only the trailing Le leaves a valid request
+            let le_len = if apdu[4] == 0 { 2 } else { 1 };
+            for len in 0..apdu.len() - le_len {
+                assert!(Request::decode(&apdu[..len]).is_err(), "length {}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_arbitrary_bytes_never_panics() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..10_000 {
+            let len = rng.gen_range(0, 160);
+            let mut apdu: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            // Mostly valid headers, so the length handling is what gets exercised
+            if apdu.len() >= 4 && rng.gen() {
+                apdu[0] = 0x00;
+                apdu[1] = [REGISTER_COMMAND_CODE, AUTHENTICATE_COMMAND_CODE][rng.gen_range(0, 2)];
+                apdu[2] = AUTH_ENFORCE;
+            }
+            let _ = Request::decode(&apdu);
+        }
     }
 }
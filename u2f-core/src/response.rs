@@ -1,9 +1,9 @@
 use std::io;
 
 use crate::attestation::AttestationCertificate;
-use byteorder::{BigEndian, WriteBytesExt};
 use crate::key_handle::KeyHandle;
 
+use super::counter_bytes;
 use super::user_presence_byte;
 use super::Counter;
 use super::SignError;
@@ -14,7 +14,7 @@ pub enum Response {
     Registration {
         user_public_key: Vec<u8>,
         key_handle: KeyHandle,
-        attestation_certificate: AttestationCertificate,
+        attestation_certificate: Option<AttestationCertificate>,
         signature: Box<dyn Signature>,
     },
     Authentication {
@@ -33,6 +33,11 @@ pub enum Response {
 
 impl Response {
     pub fn into_bytes(self) -> Vec<u8> {
+        self.encode()
+    }
+
+    /// Encodes the response message followed by its two byte status word.
+    pub fn encode(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         match self {
             Response::Registration {
@@ -41,25 +46,13 @@ impl Response {
                 attestation_certificate,
                 signature,
             } => {
-                // reserved byte [1 byte], which for legacy reasons has the value 0x05.
-                bytes.push(0x05);
-
-                // user public key [65 bytes]. This is the (uncompressed) x,y-representation of a curve point on the P-256 NIST elliptic curve.
-                bytes.extend_from_slice(&user_public_key);
-
-                // key handle length byte [1 byte], which specifies the length of the key handle (see below). The value is unsigned (range 0-255).
-                let key_handle_bytes = key_handle.as_ref();
-                bytes.push(key_handle_bytes.len() as u8);
-
-                // A key handle [length specified in previous field].
-                bytes.extend_from_slice(key_handle_bytes);
-
-                // An attestation certificate [variable length]. This is a certificate in X.509 DER format
-                bytes.extend_from_slice(&attestation_certificate.to_der());
-
-                // A signature [variable length, 71-73 bytes]
-                let signature_bytes = signature.as_ref().as_ref();
-                bytes.extend_from_slice(signature_bytes);
+                write_registration(
+                    &mut bytes,
+                    user_public_key,
+                    key_handle,
+                    attestation_certificate.as_ref(),
+                    signature.as_ref(),
+                );
 
                 // Status word [2 bytes]
                 StatusCode::NoError.write(&mut bytes);
@@ -69,16 +62,7 @@ impl Response {
                 signature,
                 user_present,
             } => {
-                let user_presence_byte = user_presence_byte(user_present);
-
-                // A user presence byte [1 byte].
-                bytes.push(user_presence_byte);
-
-                // A counter [4 bytes].
-                bytes.write_u32::<BigEndian>(counter).unwrap();
-
-                // A signature [variable length, 71-73 bytes]
-                bytes.extend_from_slice(signature.as_ref().as_ref());
+                write_authentication(&mut bytes, *counter, signature.as_ref(), *user_present);
 
                 // Status word [2 bytes]
                 StatusCode::NoError.write(&mut bytes);
@@ -113,6 +97,56 @@ impl Response {
     }
 }
 
+pub(crate) fn write_registration(
+    bytes: &mut Vec<u8>,
+    user_public_key: &[u8],
+    key_handle: &KeyHandle,
+    attestation_certificate: Option<&AttestationCertificate>,
+    signature: &dyn Signature,
+) {
+    // reserved byte [1 byte], which for legacy reasons has the value 0x05.
+    bytes.push(0x05);
+
+    // user public key [65 bytes]. This is the (uncompressed) x,y-representation of a curve point on the P-256 NIST elliptic curve.
+    bytes.extend_from_slice(user_public_key);
+
+    // key handle length byte [1 byte], which specifies the length of the key handle (see below). The value is unsigned (range 0-255).
+    let key_handle_bytes = key_handle.as_ref();
+    bytes.push(key_handle_bytes.len() as u8);
+
+    // A key handle [length specified in previous field].
+    bytes.extend_from_slice(key_handle_bytes);
+
+    // An attestation certificate [variable length]. This is a certificate in X.509 DER format.
+    // Any intermediates are appended in the same format, relying parties that expect a single
+    // certificate will fail to parse the signature, so only configure a chain when needed.
+    // Self attested registrations have no certificate at all.
+    if let Some(attestation_certificate) = attestation_certificate {
+        for certificate_der in attestation_certificate.chain_to_der() {
+            bytes.extend_from_slice(&certificate_der);
+        }
+    }
+
+    // A signature [variable length, 71-73 bytes]
+    bytes.extend_from_slice(signature.as_ref());
+}
+
+pub(crate) fn write_authentication(
+    bytes: &mut Vec<u8>,
+    counter: Counter,
+    signature: &dyn Signature,
+    user_present: bool,
+) {
+    // A user presence byte [1 byte].
+    bytes.push(user_presence_byte(user_present));
+
+    // A counter [4 bytes].
+    bytes.extend_from_slice(&counter_bytes(counter));
+
+    // A signature [variable length, 71-73 bytes]
+    bytes.extend_from_slice(signature.as_ref());
+}
+
 quick_error! {
     #[derive(Debug)]
     pub enum ResponseError {
@@ -125,11 +159,11 @@ quick_error! {
     }
 }
 
-impl Into<io::Error> for ResponseError {
-    fn into(self: Self) -> io::Error {
-        match self {
+impl From<ResponseError> for io::Error {
+    fn from(err: ResponseError) -> io::Error {
+        match err {
             ResponseError::Io(err) => err,
-            ResponseError::Signing(_) => io::Error::new(io::ErrorKind::Other, "Signing error"),
+            ResponseError::Signing(_) => io::Error::other("Signing error"),
         }
     }
 }
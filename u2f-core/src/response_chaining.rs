@@ -0,0 +1,131 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use super::StatusCode;
+
+/// The most response data one short length encoded response can carry.
+const MAX_CHUNK_LEN: usize = 256;
+
+/// A response still being collected with GET RESPONSE.
+struct Pending {
+    data: Vec<u8>,
+    status_code: StatusCode,
+}
+
+/// Holds on to responses too long for the short length encoded request that
+/// asked for them and hands them out in pieces, ISO 7816-4 style: each piece
+/// ends in SW 61XX while more data is waiting, XX being how much (or 00 for
+/// 256 bytes or more), until the last one ends in the response's own status
+/// word. Each channel has its own pending response.
+#[derive(Default)]
+pub(crate) struct ResponseChaining {
+    pending: RefCell<HashMap<u32, Pending>>,
+}
+
+impl ResponseChaining {
+    /// Starts chaining `response`, a response APDU ending in its status word,
+    /// and returns the first piece.
+    pub(crate) fn start(&self, channel: u32, mut response: Vec<u8>) -> Vec<u8> {
+        let status_word = response.split_off(response.len() - 2);
+        let mut pending = Pending {
+            data: response,
+            // Only ever given responses encoded here, whose status words are known
+            status_code: StatusCode::from_bytes([status_word[0], status_word[1]])
+                .unwrap_or(StatusCode::UnknownError),
+        };
+        let chunk = next_chunk(&mut pending, MAX_CHUNK_LEN);
+        if !pending.data.is_empty() {
+            self.pending.borrow_mut().insert(channel, pending);
+        }
+        chunk
+    }
+
+    /// Returns the next piece of at most `max_len` bytes of the response
+    /// pending on `channel`.
+    pub(crate) fn get_response(&self, channel: u32, max_len: usize) -> Vec<u8> {
+        let mut pending = self.pending.borrow_mut();
+        let chunk = match pending.get_mut(&channel) {
+            Some(response) => next_chunk(response, max_len),
+            None => {
+                let mut bytes = Vec::new();
+                StatusCode::CommandNotAllowed.write(&mut bytes);
+                return bytes;
+            }
+        };
+        if pending[&channel].data.is_empty() {
+            pending.remove(&channel);
+        }
+        chunk
+    }
+
+    /// Drops the response pending on `channel`, the client moved on to
+    /// another command.
+    pub(crate) fn cancel(&self, channel: u32) {
+        self.pending.borrow_mut().remove(&channel);
+    }
+}
+
+fn next_chunk(pending: &mut Pending, max_len: usize) -> Vec<u8> {
+    let len = max_len.min(MAX_CHUNK_LEN).min(pending.data.len());
+    let rest = pending.data.split_off(len);
+    let mut chunk = std::mem::replace(&mut pending.data, rest);
+    if pending.data.is_empty() {
+        pending.status_code.write(&mut chunk);
+    } else {
+        // 256 bytes or more are reported as 00, like an Le of zero
+        let remaining = if pending.data.len() >= MAX_CHUNK_LEN {
+            0
+        } else {
+            pending.data.len() as u8
+        };
+        StatusCode::BytesRemaining(remaining).write(&mut chunk);
+    }
+    chunk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(data_len: usize) -> Vec<u8> {
+        let mut response: Vec<u8> = (0..data_len).map(|i| i as u8).collect();
+        response.extend_from_slice(&[0x90, 0x00]);
+        response
+    }
+
+    #[test]
+    fn long_response_is_handed_out_in_pieces() {
+        let chaining = ResponseChaining::default();
+
+        let first = chaining.start(1, response(300));
+        let second = chaining.get_response(1, 256);
+
+        assert_eq!(first.len(), 256 + 2);
+        assert_eq!(first[256..], [0x61, 44]);
+        assert_eq!(second.len(), 44 + 2);
+        assert_eq!(second[44..], [0x90, 0x00]);
+        assert_eq!(
+            [&first[..256], &second[..44]].concat(),
+            &response(300)[..300]
+        );
+    }
+
+    #[test]
+    fn remaining_count_saturates_at_256_bytes() {
+        let chaining = ResponseChaining::default();
+
+        let first = chaining.start(1, response(600));
+
+        assert_eq!(first[256..], [0x61, 0x00]);
+    }
+
+    #[test]
+    fn channels_have_separate_pending_responses() {
+        let chaining = ResponseChaining::default();
+        chaining.start(1, response(300));
+
+        assert_eq!(chaining.get_response(2, 256), [0x69, 0x86]);
+        chaining.cancel(1);
+        assert_eq!(chaining.get_response(1, 256), [0x69, 0x86]);
+    }
+}
@@ -0,0 +1,94 @@
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+
+use futures::Future;
+
+use crate::app_id::AppId;
+use crate::key_handle::KeyHandle;
+
+use super::AuthenticateError;
+use super::Authentication;
+use super::Challenge;
+use super::RegisterError;
+use super::Registration;
+use super::U2F;
+
+type Job = Box<dyn FnOnce(&U2F) + Send>;
+
+/// A handle to a `U2F` running on its own thread, which can be cloned and
+/// used from any number of threads.
+///
+/// `U2F` and the stores, crypto and user presence behind it are not `Send`,
+/// so instead of sharing them the worker thread owns them and runs requests
+/// one at a time, in the order they arrive. Each request finishes before the
+/// next starts, so no two authentications can read the same counter value.
+#[derive(Clone)]
+pub struct SharedU2F {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl SharedU2F {
+    /// Starts the worker thread and builds the `U2F` on it with `build`.
+    /// The thread exits once every handle has been dropped.
+    pub fn spawn<F>(build: F) -> io::Result<SharedU2F>
+    where
+        F: FnOnce() -> io::Result<U2F> + Send + 'static,
+    {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let (started, result) = mpsc::channel();
+        thread::Builder::new().name("u2f".into()).spawn(move || {
+            let u2f = match build() {
+                Ok(u2f) => {
+                    let _ = started.send(Ok(()));
+                    u2f
+                }
+                Err(err) => {
+                    let _ = started.send(Err(err));
+                    return;
+                }
+            };
+            for job in receiver {
+                job(&u2f);
+            }
+        })?;
+        result.recv().map_err(|_| stopped())??;
+        Ok(SharedU2F { jobs })
+    }
+
+    pub fn register(
+        &self,
+        application: AppId,
+        challenge: Challenge,
+    ) -> Result<Registration, RegisterError> {
+        self.run(move |u2f| u2f.register(application, challenge).wait())?
+    }
+
+    pub fn authenticate(
+        &self,
+        application: AppId,
+        challenge: Challenge,
+        key_handle: KeyHandle,
+    ) -> Result<Authentication, AuthenticateError> {
+        self.run(move |u2f| u2f.authenticate(application, challenge, key_handle).wait())?
+    }
+
+    /// Runs `job` on the worker thread and waits for its result.
+    fn run<T, F>(&self, job: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&U2F) -> T + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.jobs
+            .send(Box::new(move |u2f| {
+                let _ = sender.send(job(u2f));
+            }))
+            .map_err(|_| stopped())?;
+        receiver.recv().map_err(|_| stopped())
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("U2F worker thread stopped")
+}
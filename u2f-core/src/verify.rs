@@ -0,0 +1,338 @@
+use byteorder::{BigEndian, ByteOrder};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::sha::sha256;
+use openssl::x509::X509;
+
+use crate::app_id::AppId;
+use crate::counter::{verify_counter, CloneDetected};
+use crate::key_handle::KeyHandle;
+use crate::public_key::PublicKey;
+
+use super::message_to_sign_for_authenticate;
+use super::message_to_sign_for_register;
+use super::user_presence_byte;
+use super::Challenge;
+use super::Counter;
+
+const REGISTRATION_RESERVED_BYTE: u8 = 0x05;
+const USER_PUBLIC_KEY_LEN: usize = 65;
+const COUNTER_LEN: usize = 4;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum VerifyError {
+        Malformed(reason: &'static str) {
+            display("Malformed response: {}", reason)
+        }
+        Certificate(err: ErrorStack) {
+            cause(err)
+            display("Unable to read attestation certificate: {}", err)
+        }
+        InvalidPublicKey(reason: String) {
+            display("Invalid user public key: {}", reason)
+        }
+        BadSignature {
+            display("Signature does not match")
+        }
+        UserNotPresent {
+            display("Response does not assert user presence")
+        }
+        CloneDetected(err: CloneDetected) {
+            from()
+            cause(err)
+            display("{}", err)
+        }
+    }
+}
+
+/// The parts of a verified registration a relying party keeps.
+#[derive(Debug)]
+pub struct VerifiedRegistration {
+    /// Uncompressed P-256 point, for `verify_signature`
+    pub user_public_key: Vec<u8>,
+    pub key_handle: KeyHandle,
+    pub attestation_certificate: X509,
+}
+
+/// For relying parties: parses a raw registration response message, as
+/// `Registration::to_bytes` encodes it, and checks the attestation signature
+/// covers the given application and challenge. Whether to trust the
+/// attestation certificate itself is left to the caller. Responses with
+/// intermediates after the certificate, or without a certificate because they
+/// are self attested, are not accepted.
+pub fn verify_registration(
+    response: &[u8],
+    application: &AppId,
+    challenge: &Challenge,
+) -> Result<VerifiedRegistration, VerifyError> {
+    let (&reserved, rest) = response
+        .split_first()
+        .ok_or(VerifyError::Malformed("empty"))?;
+    if reserved != REGISTRATION_RESERVED_BYTE {
+        return Err(VerifyError::Malformed("unexpected reserved byte"));
+    }
+    if rest.len() < USER_PUBLIC_KEY_LEN + 1 {
+        return Err(VerifyError::Malformed("truncated user public key"));
+    }
+    let (user_public_key, rest) = rest.split_at(USER_PUBLIC_KEY_LEN);
+    let (&key_handle_len, rest) = rest.split_first().unwrap();
+    if rest.len() < key_handle_len as usize {
+        return Err(VerifyError::Malformed("truncated key handle"));
+    }
+    let (key_handle, rest) = rest.split_at(key_handle_len as usize);
+
+    // The certificate's own DER header says where the signature starts
+    let attestation_certificate = X509::from_der(rest).map_err(VerifyError::Certificate)?;
+    let certificate_len = attestation_certificate
+        .to_der()
+        .map_err(VerifyError::Certificate)?
+        .len();
+    let signature = EcdsaSig::from_der(&rest[certificate_len..])
+        .map_err(|_| VerifyError::Malformed("signature is not valid DER"))?;
+
+    let key_handle = KeyHandle::from(key_handle);
+    let message =
+        message_to_sign_for_register(application, challenge, user_public_key, &key_handle);
+    let attestation_key = attestation_certificate
+        .public_key()
+        .and_then(|key| key.ec_key())
+        .map_err(VerifyError::Certificate)?;
+    if !signature
+        .verify(&sha256(&message), &attestation_key)
+        .map_err(VerifyError::Certificate)?
+    {
+        return Err(VerifyError::BadSignature);
+    }
+
+    Ok(VerifiedRegistration {
+        user_public_key: user_public_key.to_vec(),
+        key_handle,
+        attestation_certificate,
+    })
+}
+
+/// For relying parties: parses a raw authentication response message and
+/// checks it asserts user presence, is signed over the given application and
+/// challenge by the credential's key, and has a counter greater than
+/// `last_counter`. Returns the new counter to store for the credential.
+pub fn verify_authentication(
+    response: &[u8],
+    application: &AppId,
+    challenge: &Challenge,
+    user_public_key: &[u8],
+    last_counter: Counter,
+) -> Result<Counter, VerifyError> {
+    if response.len() < 1 + COUNTER_LEN {
+        return Err(VerifyError::Malformed("truncated counter"));
+    }
+    let (&user_presence, rest) = response.split_first().unwrap();
+    let (counter, signature) = rest.split_at(COUNTER_LEN);
+    if user_presence != user_presence_byte(true) {
+        return Err(VerifyError::UserNotPresent);
+    }
+    let counter = BigEndian::read_u32(counter);
+    let signature = EcdsaSig::from_der(signature)
+        .map_err(|_| VerifyError::Malformed("signature is not valid DER"))?;
+
+    let public_key =
+        PublicKey::from_bytes(user_public_key).map_err(VerifyError::InvalidPublicKey)?;
+    let message = message_to_sign_for_authenticate(application, challenge, user_presence, counter);
+    if !signature
+        .verify(&sha256(&message), public_key.as_ec_key())
+        .map_err(|err| VerifyError::InvalidPublicKey(err.to_string()))?
+    {
+        return Err(VerifyError::BadSignature);
+    }
+
+    verify_counter(last_counter, counter)?;
+    Ok(counter)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use futures::future;
+    use futures::Future;
+
+    use crate::self_signed_attestation::self_signed_attestation;
+    use crate::{ApprovalError, InMemoryStore, SecureCryptoOperations, UserPresence, U2F};
+
+    use super::*;
+
+    struct AlwaysPresent;
+
+    impl UserPresence for AlwaysPresent {
+        fn approve_registration(
+            &self,
+            _: &AppId,
+        ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+            Box::new(future::ok(true))
+        }
+        fn approve_authentication(
+            &self,
+            _: &AppId,
+        ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+            Box::new(future::ok(true))
+        }
+        fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
+            Box::new(future::ok(()))
+        }
+    }
+
+    fn test_u2f() -> U2F {
+        U2F::new(
+            Box::new(AlwaysPresent),
+            Box::new(SecureCryptoOperations::new(self_signed_attestation())),
+            Box::new(InMemoryStore::new()),
+            None,
+        )
+        .unwrap()
+    }
+
+    fn registration_bytes(application: &AppId, challenge: &Challenge) -> Vec<u8> {
+        test_u2f()
+            .register(*application, challenge.clone())
+            .wait()
+            .unwrap()
+            .to_bytes()
+            .unwrap()
+    }
+
+    /// Registers and authenticates once, returning the user public key, the
+    /// raw authentication response and its counter.
+    fn authentication(application: &AppId, challenge: &Challenge) -> (Vec<u8>, Vec<u8>, Counter) {
+        let u2f = test_u2f();
+        let registration = u2f
+            .register(*application, Challenge::from_client_data(b"{}"))
+            .wait()
+            .unwrap();
+        let authentication = u2f
+            .authenticate(
+                *application,
+                challenge.clone(),
+                registration.key_handle().clone(),
+            )
+            .wait()
+            .unwrap();
+        (
+            registration.user_public_key().to_vec(),
+            authentication.to_bytes(),
+            authentication.counter(),
+        )
+    }
+
+    #[test]
+    fn genuine_registration_is_verified() {
+        let application = AppId::from_app_id("example.com");
+        let challenge = Challenge::from_client_data(b"{}");
+        let response = registration_bytes(&application, &challenge);
+
+        let verified = verify_registration(&response, &application, &challenge).unwrap();
+
+        assert_eq!(&verified.user_public_key[..], &response[1..66]);
+        assert_eq!(
+            verified.key_handle.as_ref(),
+            &response[67..67 + response[66] as usize]
+        );
+    }
+
+    #[test]
+    fn flipped_signature_byte_is_rejected() {
+        let application = AppId::from_app_id("example.com");
+        let challenge = Challenge::from_client_data(b"{}");
+        let mut response = registration_bytes(&application, &challenge);
+        let last = response.len() - 1;
+        response[last] ^= 0x01;
+
+        assert_matches!(
+            verify_registration(&response, &application, &challenge),
+            Err(VerifyError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn registration_for_other_challenge_is_rejected() {
+        let application = AppId::from_app_id("example.com");
+        let response = registration_bytes(&application, &Challenge::from_client_data(b"{}"));
+
+        assert_matches!(
+            verify_registration(
+                &response,
+                &application,
+                &Challenge::from_client_data(b"{\"other\":1}")
+            ),
+            Err(VerifyError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn truncated_registration_errors() {
+        let application = AppId::from_app_id("example.com");
+        let challenge = Challenge::from_client_data(b"{}");
+        let response = registration_bytes(&application, &challenge);
+
+        assert_matches!(
+            verify_registration(&response[..40], &application, &challenge),
+            Err(VerifyError::Malformed(_))
+        );
+    }
+
+    #[test]
+    fn genuine_authentication_is_verified() {
+        let application = AppId::from_app_id("example.com");
+        let challenge = Challenge::from_client_data(b"{\"type\":\"navigator.id.getAssertion\"}");
+        let (user_public_key, response, counter) = authentication(&application, &challenge);
+
+        assert_eq!(
+            verify_authentication(&response, &application, &challenge, &user_public_key, 0)
+                .unwrap(),
+            counter
+        );
+    }
+
+    #[test]
+    fn stale_counter_is_rejected() {
+        let application = AppId::from_app_id("example.com");
+        let challenge = Challenge::from_client_data(b"{}");
+        let (user_public_key, response, counter) = authentication(&application, &challenge);
+
+        assert_matches!(
+            verify_authentication(
+                &response,
+                &application,
+                &challenge,
+                &user_public_key,
+                counter
+            ),
+            Err(VerifyError::CloneDetected(_))
+        );
+    }
+
+    #[test]
+    fn authentication_with_other_public_key_is_rejected() {
+        let application = AppId::from_app_id("example.com");
+        let challenge = Challenge::from_client_data(b"{}");
+        let (_, response, _) = authentication(&application, &challenge);
+        let (other_public_key, _, _) = authentication(&application, &challenge);
+
+        assert_matches!(
+            verify_authentication(&response, &application, &challenge, &other_public_key, 0),
+            Err(VerifyError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn authentication_without_user_presence_is_rejected() {
+        let application = AppId::from_app_id("example.com");
+        let challenge = Challenge::from_client_data(b"{}");
+        let (user_public_key, mut response, _) = authentication(&application, &challenge);
+        response[0] = user_presence_byte(false);
+
+        assert_matches!(
+            verify_authentication(&response, &application, &challenge, &user_public_key, 0),
+            Err(VerifyError::UserNotPresent)
+        );
+    }
+}
@@ -0,0 +1,797 @@
+//! CTAP2 commands mapped onto the U2F operations, so that WebAuthn clients
+//! can use the same keys. Attestation uses the "fido-u2f" format, which is
+//! exactly the U2F registration signature. Likewise a U2F authentication
+//! signature covers `authData || clientDataHash` when the only flag is UP.
+
+use std::io;
+
+use futures::future;
+use futures::Future;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+
+use crate::app_id::AppId;
+use crate::attestation::AttestationMode;
+use crate::cbor::Value;
+use crate::key_handle::KeyHandle;
+use crate::{
+    counter_bytes, AuthenticateError, Authentication, Challenge, RegisterError, Registration, U2F,
+};
+
+/// COSE algorithm identifier for ECDSA using P-256 and SHA-256
+const COSE_ALGORITHM_ES256: i64 = -7;
+/// COSE algorithm identifier for ECDSA using P-384 and SHA-384
+const COSE_ALGORITHM_ES384: i64 = -35;
+/// COSE algorithm identifier for ECDSA using P-521 and SHA-512
+const COSE_ALGORITHM_ES512: i64 = -36;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+pub struct MakeCredentialRequest {
+    pub rp_id_hash: AppId,
+    pub client_data_hash: Challenge,
+    /// COSE algorithm identifiers the relying party accepts
+    pub algorithms: Vec<i64>,
+    /// `None` when the relying party asked for "none" attestation
+    pub attestation: AttestationMode,
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum MakeCredentialError {
+        UnsupportedAlgorithm
+        Register(err: RegisterError) {
+            from()
+        }
+    }
+}
+
+pub struct GetAssertionRequest {
+    pub rp_id_hash: AppId,
+    pub client_data_hash: Challenge,
+    /// Credential ids the relying party accepts, in order of preference
+    pub allow_list: Vec<KeyHandle>,
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum GetAssertionError {
+        NoCredentials
+        Authenticate(err: AuthenticateError) {
+            from()
+        }
+        Io(err: io::Error) {
+            from()
+        }
+    }
+}
+
+impl U2F {
+    /// Handles authenticatorMakeCredential, returning the CBOR encoded
+    /// attestation object.
+    pub fn make_credential(
+        &self,
+        request: MakeCredentialRequest,
+    ) -> Box<dyn Future<Item = Vec<u8>, Error = MakeCredentialError>> {
+        let key_type = match CoseKeyType::new(self.0.operations.curve(), self.0.operations.digest())
+        {
+            Some(key_type) if request.algorithms.contains(&key_type.algorithm) => key_type,
+            _ => return Box::new(future::err(MakeCredentialError::UnsupportedAlgorithm)),
+        };
+        // fido-u2f attestation only covers P-256 credentials
+        let attestation = if key_type.algorithm == COSE_ALGORITHM_ES256 {
+            request.attestation
+        } else {
+            AttestationMode::None
+        };
+
+        let rp_id_hash = request.rp_id_hash;
+        let aaguid = self.0.operations.aaguid();
+        Box::new(
+            self.register_on_any_curve(request.rp_id_hash, request.client_data_hash, attestation)
+                .from_err()
+                .map(move |registration| {
+                    attestation_object(&rp_id_hash, &aaguid, &key_type, &registration)
+                }),
+        )
+    }
+
+    /// Handles authenticatorGetAssertion using the first credential in the
+    /// allow list that belongs to the relying party, returning the CBOR
+    /// encoded response. With key wrapping, a credential ID that unwraps under
+    /// the device key for this rpId hash is accepted without a stored key.
+    pub fn get_assertion(
+        &self,
+        request: GetAssertionRequest,
+    ) -> Box<dyn Future<Item = Vec<u8>, Error = GetAssertionError>> {
+        let mut credential_id = None;
+        for key_handle in request.allow_list {
+            match self.is_valid_key_handle(&key_handle, &request.rp_id_hash) {
+                Ok(true) => {
+                    credential_id = Some(key_handle);
+                    break;
+                }
+                Ok(false) => {}
+                Err(err) => return Box::new(future::err(err.into())),
+            }
+        }
+        let credential_id = match credential_id {
+            Some(credential_id) => credential_id,
+            None => return Box::new(future::err(GetAssertionError::NoCredentials)),
+        };
+
+        let rp_id_hash = request.rp_id_hash;
+        Box::new(
+            self.authenticate(
+                request.rp_id_hash,
+                request.client_data_hash,
+                credential_id.clone(),
+            )
+            .from_err()
+            .map(move |authentication| assertion(&rp_id_hash, &credential_id, &authentication)),
+        )
+    }
+}
+
+fn assertion(
+    rp_id_hash: &AppId,
+    credential_id: &KeyHandle,
+    authentication: &Authentication,
+) -> Vec<u8> {
+    let credential = Value::Map(vec![
+        (
+            Value::text("id"),
+            Value::Bytes(credential_id.as_ref().to_vec()),
+        ),
+        (Value::text("type"), Value::text("public-key")),
+    ]);
+
+    Value::Map(vec![
+        (Value::Integer(1), credential),
+        (
+            Value::Integer(2),
+            Value::Bytes(get_assertion_authenticator_data(rp_id_hash, authentication)),
+        ),
+        (
+            Value::Integer(3),
+            Value::Bytes(authentication.signature.as_ref().as_ref().to_vec()),
+        ),
+    ])
+    .to_bytes()
+}
+
+fn get_assertion_authenticator_data(
+    rp_id_hash: &AppId,
+    authentication: &Authentication,
+) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(rp_id_hash.as_ref());
+    let mut flags = 0;
+    if authentication.user_present {
+        flags |= FLAG_USER_PRESENT;
+    }
+    if authentication.user_verified {
+        flags |= FLAG_USER_VERIFIED;
+    }
+    data.push(flags);
+    data.extend_from_slice(&counter_bytes(authentication.counter));
+    data
+}
+
+/// How a credential public key is labelled in its COSE_Key.
+struct CoseKeyType {
+    algorithm: i64,
+    curve: i64,
+    coordinate_len: usize,
+}
+
+impl CoseKeyType {
+    /// WebAuthn pairs each curve with a single digest, other combinations
+    /// have no algorithm identifier.
+    fn new(curve: Nid, digest: MessageDigest) -> Option<CoseKeyType> {
+        let (algorithm, cose_curve, coordinate_len, digest_nid) = match curve {
+            Nid::X9_62_PRIME256V1 => (COSE_ALGORITHM_ES256, 1, 32, Nid::SHA256),
+            Nid::SECP384R1 => (COSE_ALGORITHM_ES384, 2, 48, Nid::SHA384),
+            Nid::SECP521R1 => (COSE_ALGORITHM_ES512, 3, 66, Nid::SHA512),
+            _ => return None,
+        };
+        if digest.type_() != digest_nid {
+            return None;
+        }
+        Some(CoseKeyType {
+            algorithm,
+            curve: cose_curve,
+            coordinate_len,
+        })
+    }
+}
+
+fn attestation_object(
+    rp_id_hash: &AppId,
+    aaguid: &[u8; 16],
+    key_type: &CoseKeyType,
+    registration: &Registration,
+) -> Vec<u8> {
+    // fido-u2f requires a certificate, and a self signed U2F registration
+    // message is no use to a relying party expecting packed self attestation
+    let (format, attestation_statement) = match registration.attestation_certificate {
+        Some(ref attestation_certificate) => (
+            "fido-u2f",
+            Value::Map(vec![
+                (
+                    Value::text("sig"),
+                    Value::Bytes(registration.signature.as_ref().as_ref().to_vec()),
+                ),
+                (
+                    Value::text("x5c"),
+                    Value::Array(
+                        attestation_certificate
+                            .chain_to_der()
+                            .into_iter()
+                            .map(Value::Bytes)
+                            .collect(),
+                    ),
+                ),
+            ]),
+        ),
+        None => ("none", Value::Map(Vec::new())),
+    };
+
+    Value::Map(vec![
+        (Value::text("fmt"), Value::text(format)),
+        (Value::text("attStmt"), attestation_statement),
+        (
+            Value::text("authData"),
+            Value::Bytes(make_credential_authenticator_data(
+                rp_id_hash,
+                aaguid,
+                key_type,
+                registration,
+            )),
+        ),
+    ])
+    .to_bytes()
+}
+
+fn make_credential_authenticator_data(
+    rp_id_hash: &AppId,
+    aaguid: &[u8; 16],
+    key_type: &CoseKeyType,
+    registration: &Registration,
+) -> Vec<u8> {
+    let credential_id = registration.key_handle.as_ref();
+
+    let mut data = Vec::new();
+    data.extend_from_slice(rp_id_hash.as_ref());
+    data.push(FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL_DATA);
+    // U2F registration does not use the signature counter
+    data.extend_from_slice(&[0u8; 4]);
+    data.extend_from_slice(aaguid);
+    data.push((credential_id.len() >> 8) as u8);
+    data.push(credential_id.len() as u8);
+    data.extend_from_slice(credential_id);
+    data.extend_from_slice(&cose_public_key(key_type, &registration.user_public_key).to_bytes());
+    data
+}
+
+/// Converts an uncompressed EC point into a COSE_Key, with the map keys in
+/// CTAP2 canonical order.
+fn cose_public_key(key_type: &CoseKeyType, user_public_key: &[u8]) -> Value {
+    let (x, y) = user_public_key[1..].split_at(key_type.coordinate_len);
+    Value::Map(vec![
+        // kty: EC2
+        (Value::Integer(1), Value::Integer(2)),
+        (Value::Integer(3), Value::Integer(key_type.algorithm)),
+        (Value::Integer(-1), Value::Integer(key_type.curve)),
+        (Value::Integer(-2), Value::Bytes(x.to_vec())),
+        (Value::Integer(-3), Value::Bytes(y.to_vec())),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use openssl::bn::BigNumContext;
+    use openssl::ec::{EcGroup, EcKey, EcPoint};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::sign::Verifier;
+    use openssl::x509::X509;
+
+    use crate::cbor;
+    use crate::{
+        self_signed_attestation, ApprovalError, InMemoryStore, KeyWrappingKey,
+        SecureCryptoOperations, UserPresence, UserVerification,
+    };
+
+    use super::*;
+
+    struct AlwaysPresent;
+
+    impl UserPresence for AlwaysPresent {
+        fn approve_registration(
+            &self,
+            _: &AppId,
+        ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+            Box::new(future::ok(true))
+        }
+        fn approve_authentication(
+            &self,
+            _: &AppId,
+        ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+            Box::new(future::ok(true))
+        }
+        fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
+            Box::new(future::ok(()))
+        }
+    }
+
+    /// Verifies the user, as a PIN or biometric prompt would.
+    struct AlwaysVerified;
+
+    impl UserPresence for AlwaysVerified {
+        fn approve_registration(
+            &self,
+            _: &AppId,
+        ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+            Box::new(future::ok(true))
+        }
+        fn approve_authentication(
+            &self,
+            _: &AppId,
+        ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+            Box::new(future::ok(true))
+        }
+        fn approve_authentication_with_verification(
+            &self,
+            _: &AppId,
+        ) -> Box<dyn Future<Item = Option<UserVerification>, Error = ApprovalError>> {
+            Box::new(future::ok(Some(UserVerification::Verified)))
+        }
+        fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
+            Box::new(future::ok(()))
+        }
+    }
+
+    fn test_u2f() -> U2F {
+        test_u2f_with(Box::new(AlwaysPresent))
+    }
+
+    fn test_u2f_with(user_presence: Box<dyn UserPresence>) -> U2F {
+        test_u2f_with_operations(
+            user_presence,
+            SecureCryptoOperations::new(self_signed_attestation()),
+        )
+    }
+
+    fn test_u2f_with_operations(
+        user_presence: Box<dyn UserPresence>,
+        operations: SecureCryptoOperations,
+    ) -> U2F {
+        U2F::new(
+            user_presence,
+            Box::new(operations),
+            Box::new(InMemoryStore::new()),
+            None,
+        )
+        .unwrap()
+    }
+
+    fn map_get<'a>(map: &'a Value, key: &Value) -> &'a Value {
+        match map {
+            Value::Map(entries) => &entries.iter().find(|(k, _)| k == key).unwrap().1,
+            value => panic!("Expected map, found {:?}", value),
+        }
+    }
+
+    fn bytes(value: &Value) -> &[u8] {
+        match value {
+            Value::Bytes(bytes) => bytes,
+            value => panic!("Expected bytes, found {:?}", value),
+        }
+    }
+
+    fn make_credential_request() -> MakeCredentialRequest {
+        MakeCredentialRequest {
+            rp_id_hash: AppId::from_app_id("example.com"),
+            client_data_hash: Challenge::from_client_data(b"{}"),
+            algorithms: vec![-257, COSE_ALGORITHM_ES256],
+            attestation: AttestationMode::Direct,
+        }
+    }
+
+    #[test]
+    fn make_credential_attestation_object_structure() {
+        let request = make_credential_request();
+        let rp_id_hash = request.rp_id_hash;
+        let client_data_hash = request.client_data_hash.clone();
+
+        let attestation_object =
+            cbor::decode(&test_u2f().make_credential(request).wait().unwrap()).unwrap();
+
+        assert_eq!(
+            map_get(&attestation_object, &Value::text("fmt")),
+            &Value::text("fido-u2f")
+        );
+
+        let auth_data = bytes(map_get(&attestation_object, &Value::text("authData")));
+        assert_eq!(&auth_data[..32], rp_id_hash.as_ref());
+        assert_eq!(
+            auth_data[32],
+            FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL_DATA
+        );
+        assert_eq!(&auth_data[37..53], &[0u8; 16]);
+        let credential_id_len = ((auth_data[53] as usize) << 8) | auth_data[54] as usize;
+        let credential_id = &auth_data[55..55 + credential_id_len];
+        let public_key = cbor::decode(&auth_data[55 + credential_id_len..]).unwrap();
+        assert_eq!(
+            map_get(&public_key, &Value::Integer(3)),
+            &Value::Integer(COSE_ALGORITHM_ES256)
+        );
+        let mut user_public_key = vec![0x04];
+        user_public_key.extend_from_slice(bytes(map_get(&public_key, &Value::Integer(-2))));
+        user_public_key.extend_from_slice(bytes(map_get(&public_key, &Value::Integer(-3))));
+
+        // fido-u2f signs the U2F registration message
+        let attestation_statement = map_get(&attestation_object, &Value::text("attStmt"));
+        let certificate = match map_get(attestation_statement, &Value::text("x5c")) {
+            Value::Array(certificates) => X509::from_der(bytes(&certificates[0])).unwrap(),
+            value => panic!("Expected array, found {:?}", value),
+        };
+        let mut message = vec![0x00];
+        message.extend_from_slice(rp_id_hash.as_ref());
+        message.extend_from_slice(client_data_hash.as_ref());
+        message.extend_from_slice(credential_id);
+        message.extend_from_slice(&user_public_key);
+        let public_key = certificate.public_key().unwrap();
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key).unwrap();
+        verifier.update(&message).unwrap();
+        assert!(verifier
+            .verify(bytes(map_get(attestation_statement, &Value::text("sig"))))
+            .unwrap());
+    }
+
+    #[test]
+    fn make_credential_includes_configured_aaguid() {
+        let aaguid = [
+            0x5a, 0x3c, 0x1f, 0x2e, 0x77, 0x41, 0x4b, 0x09, 0x9d, 0x6e, 0x02, 0xc8, 0x31, 0xf0,
+            0x4a, 0xbe,
+        ];
+        let operations = SecureCryptoOperations::new(self_signed_attestation()).with_aaguid(aaguid);
+        let u2f = test_u2f_with_operations(Box::new(AlwaysPresent), operations);
+
+        let response = u2f
+            .make_credential(make_credential_request())
+            .wait()
+            .unwrap();
+        let attestation_object = cbor::decode(&response).unwrap();
+
+        let auth_data = bytes(map_get(&attestation_object, &Value::text("authData")));
+        // rpIdHash (32) || flags (1) || signCount (4) || aaguid (16)
+        assert_eq!(&auth_data[37..53], &aaguid);
+    }
+
+    #[test]
+    fn make_credential_without_es256_errors() {
+        let mut request = make_credential_request();
+        request.algorithms = vec![-257];
+
+        assert_matches!(
+            test_u2f().make_credential(request).wait(),
+            Err(MakeCredentialError::UnsupportedAlgorithm)
+        );
+    }
+
+    #[test]
+    fn make_credential_on_p384_labels_key_as_es384() {
+        let operations = SecureCryptoOperations::new(self_signed_attestation())
+            .with_curve(Nid::SECP384R1)
+            .with_digest(MessageDigest::sha384());
+        let u2f = test_u2f_with_operations(Box::new(AlwaysPresent), operations);
+        let mut request = make_credential_request();
+        request.algorithms = vec![COSE_ALGORITHM_ES384];
+
+        let attestation_object =
+            cbor::decode(&u2f.make_credential(request).wait().unwrap()).unwrap();
+
+        // fido-u2f attestation is only defined for P-256 credentials
+        assert_eq!(
+            map_get(&attestation_object, &Value::text("fmt")),
+            &Value::text("none")
+        );
+        let auth_data = bytes(map_get(&attestation_object, &Value::text("authData")));
+        let credential_id_len = ((auth_data[53] as usize) << 8) | auth_data[54] as usize;
+        let public_key = cbor::decode(&auth_data[55 + credential_id_len..]).unwrap();
+        assert_eq!(
+            map_get(&public_key, &Value::Integer(3)),
+            &Value::Integer(COSE_ALGORITHM_ES384)
+        );
+        assert_eq!(
+            map_get(&public_key, &Value::Integer(-1)),
+            &Value::Integer(2)
+        );
+        let mut point = vec![0x04];
+        point.extend_from_slice(bytes(map_get(&public_key, &Value::Integer(-2))));
+        point.extend_from_slice(bytes(map_get(&public_key, &Value::Integer(-3))));
+        assert_eq!(point.len(), 97);
+        let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+        let mut ctx = BigNumContext::new().unwrap();
+        assert!(EcPoint::from_bytes(&group, &point, &mut ctx).is_ok());
+    }
+
+    #[test]
+    fn make_credential_on_p384_without_es384_errors() {
+        let operations = SecureCryptoOperations::new(self_signed_attestation())
+            .with_curve(Nid::SECP384R1)
+            .with_digest(MessageDigest::sha384());
+        let u2f = test_u2f_with_operations(Box::new(AlwaysPresent), operations);
+
+        assert_matches!(
+            u2f.make_credential(make_credential_request()).wait(),
+            Err(MakeCredentialError::UnsupportedAlgorithm)
+        );
+    }
+
+    #[test]
+    fn register_on_p384_errors() {
+        let operations =
+            SecureCryptoOperations::new(self_signed_attestation()).with_curve(Nid::SECP384R1);
+        let u2f = test_u2f_with_operations(Box::new(AlwaysPresent), operations);
+
+        assert_matches!(
+            u2f.register(
+                AppId::from_app_id("example.com"),
+                Challenge::from_client_data(b"{}")
+            )
+            .wait(),
+            Err(RegisterError::UnsupportedCurve)
+        );
+    }
+
+    #[test]
+    fn make_credential_without_attestation_uses_none_format() {
+        let mut request = make_credential_request();
+        request.attestation = AttestationMode::None;
+
+        let attestation_object =
+            cbor::decode(&test_u2f().make_credential(request).wait().unwrap()).unwrap();
+
+        assert_eq!(
+            map_get(&attestation_object, &Value::text("fmt")),
+            &Value::text("none")
+        );
+        assert_eq!(
+            map_get(&attestation_object, &Value::text("attStmt")),
+            &Value::Map(Vec::new())
+        );
+    }
+
+    #[test]
+    fn get_assertion_signs_authenticator_data_and_client_data_hash() {
+        let u2f = test_u2f();
+        let rp_id_hash = AppId::from_app_id("example.com");
+        let registration = u2f
+            .register(rp_id_hash, Challenge::from_client_data(b"{}"))
+            .wait()
+            .unwrap();
+        let client_data_hash = Challenge::from_client_data(b"{\"type\":\"webauthn.get\"}");
+
+        let response = cbor::decode(
+            &u2f.get_assertion(GetAssertionRequest {
+                rp_id_hash,
+                client_data_hash: client_data_hash.clone(),
+                allow_list: vec![KeyHandle::from(&[9u8; 32]), registration.key_handle.clone()],
+            })
+            .wait()
+            .unwrap(),
+        )
+        .unwrap();
+
+        let credential = map_get(&response, &Value::Integer(1));
+        assert_eq!(
+            bytes(map_get(credential, &Value::text("id"))),
+            registration.key_handle.as_ref()
+        );
+        let auth_data = bytes(map_get(&response, &Value::Integer(2)));
+        assert_eq!(auth_data.len(), 32 + 1 + 4);
+        assert_eq!(&auth_data[..32], rp_id_hash.as_ref());
+        assert_eq!(auth_data[32], FLAG_USER_PRESENT);
+        assert_eq!(&auth_data[33..], &[0, 0, 0, 1]);
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let mut ctx = BigNumContext::new().unwrap();
+        let point = EcPoint::from_bytes(&group, &registration.user_public_key, &mut ctx).unwrap();
+        let public_key =
+            PKey::from_ec_key(EcKey::from_public_key(&group, &point).unwrap()).unwrap();
+        let mut message = auth_data.to_vec();
+        message.extend_from_slice(client_data_hash.as_ref());
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key).unwrap();
+        verifier.update(&message).unwrap();
+        assert!(verifier
+            .verify(bytes(map_get(&response, &Value::Integer(3))))
+            .unwrap());
+    }
+
+    #[test]
+    fn get_assertion_without_matching_credential_errors() {
+        let request = GetAssertionRequest {
+            rp_id_hash: AppId::from_app_id("example.com"),
+            client_data_hash: Challenge::from_client_data(b"{}"),
+            allow_list: vec![KeyHandle::from(&[9u8; 32])],
+        };
+
+        assert_matches!(
+            test_u2f().get_assertion(request).wait(),
+            Err(GetAssertionError::NoCredentials)
+        );
+    }
+
+    fn key_wrapping_u2f() -> U2F {
+        test_u2f_with_operations(
+            Box::new(AlwaysPresent),
+            SecureCryptoOperations::new(self_signed_attestation())
+                .with_key_wrapping(KeyWrappingKey::from_bytes([3u8; 32])),
+        )
+    }
+
+    fn asserted_credential_id(u2f: &U2F, rp_id_hash: AppId, allow_list: Vec<KeyHandle>) -> Vec<u8> {
+        let response = cbor::decode(
+            &u2f.get_assertion(GetAssertionRequest {
+                rp_id_hash,
+                client_data_hash: Challenge::from_client_data(b"{}"),
+                allow_list,
+            })
+            .wait()
+            .unwrap(),
+        )
+        .unwrap();
+        let credential = map_get(&response, &Value::Integer(1));
+        bytes(map_get(credential, &Value::text("id"))).to_vec()
+    }
+
+    #[test]
+    fn get_assertion_uses_first_wrapped_credential_for_rp() {
+        let u2f = key_wrapping_u2f();
+        let rp_id_hash = AppId::from_app_id("example.com");
+        let foreign = u2f
+            .register(
+                AppId::from_app_id("example.org"),
+                Challenge::from_client_data(b"{}"),
+            )
+            .wait()
+            .unwrap();
+        let registration = u2f
+            .register(rp_id_hash, Challenge::from_client_data(b"{}"))
+            .wait()
+            .unwrap();
+        let garbage = KeyHandle::from(&vec![0x5a; registration.key_handle.len()]);
+
+        let credential_id = asserted_credential_id(
+            &u2f,
+            rp_id_hash,
+            vec![garbage, foreign.key_handle, registration.key_handle.clone()],
+        );
+
+        assert_eq!(&credential_id[..], registration.key_handle.as_ref());
+    }
+
+    #[test]
+    fn get_assertion_rejects_credential_wrapped_for_other_rp() {
+        let u2f = key_wrapping_u2f();
+        let foreign = u2f
+            .register(
+                AppId::from_app_id("example.org"),
+                Challenge::from_client_data(b"{}"),
+            )
+            .wait()
+            .unwrap();
+
+        let request = GetAssertionRequest {
+            rp_id_hash: AppId::from_app_id("example.com"),
+            client_data_hash: Challenge::from_client_data(b"{}"),
+            allow_list: vec![foreign.key_handle],
+        };
+
+        assert_matches!(
+            u2f.get_assertion(request).wait(),
+            Err(GetAssertionError::NoCredentials)
+        );
+    }
+
+    #[test]
+    fn get_assertion_rejects_garbage_credential_id() {
+        let u2f = key_wrapping_u2f();
+        let rp_id_hash = AppId::from_app_id("example.com");
+        let registration = u2f
+            .register(rp_id_hash, Challenge::from_client_data(b"{}"))
+            .wait()
+            .unwrap();
+        let mut tampered = registration.key_handle.as_ref().to_vec();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0x01;
+
+        let request = GetAssertionRequest {
+            rp_id_hash,
+            client_data_hash: Challenge::from_client_data(b"{}"),
+            allow_list: vec![KeyHandle::from(&tampered)],
+        };
+
+        assert_matches!(
+            u2f.get_assertion(request).wait(),
+            Err(GetAssertionError::NoCredentials)
+        );
+    }
+
+    #[test]
+    fn wrapped_credential_asserts_without_stored_key() {
+        let rp_id_hash = AppId::from_app_id("example.com");
+        let registration = key_wrapping_u2f()
+            .register(rp_id_hash, Challenge::from_client_data(b"{}"))
+            .wait()
+            .unwrap();
+        let client_data_hash = Challenge::from_client_data(b"{\"type\":\"webauthn.get\"}");
+
+        // Same device key, but a store that has never seen the credential
+        let u2f = key_wrapping_u2f();
+        assert!(u2f
+            .is_valid_key_handle(&registration.key_handle, &rp_id_hash)
+            .unwrap());
+        let response = cbor::decode(
+            &u2f.get_assertion(GetAssertionRequest {
+                rp_id_hash,
+                client_data_hash: client_data_hash.clone(),
+                allow_list: vec![registration.key_handle.clone()],
+            })
+            .wait()
+            .unwrap(),
+        )
+        .unwrap();
+
+        let auth_data = bytes(map_get(&response, &Value::Integer(2)));
+        assert_eq!(&auth_data[33..], &[0, 0, 0, 1]);
+        let mut message = auth_data.to_vec();
+        message.extend_from_slice(client_data_hash.as_ref());
+        assert!(crate::verify_signature(
+            &registration.user_public_key,
+            &message,
+            bytes(map_get(&response, &Value::Integer(3))),
+        )
+        .unwrap());
+    }
+
+    fn assertion_flags(u2f: &U2F) -> u8 {
+        let rp_id_hash = AppId::from_app_id("example.com");
+        let registration = u2f
+            .register(rp_id_hash, Challenge::from_client_data(b"{}"))
+            .wait()
+            .unwrap();
+        let response = cbor::decode(
+            &u2f.get_assertion(GetAssertionRequest {
+                rp_id_hash,
+                client_data_hash: Challenge::from_client_data(b"{}"),
+                allow_list: vec![registration.key_handle.clone()],
+            })
+            .wait()
+            .unwrap(),
+        )
+        .unwrap();
+        bytes(map_get(&response, &Value::Integer(2)))[32]
+    }
+
+    #[test]
+    fn get_assertion_with_verified_user_sets_user_verified_flag() {
+        assert_eq!(
+            assertion_flags(&test_u2f_with(Box::new(AlwaysVerified))),
+            FLAG_USER_PRESENT | FLAG_USER_VERIFIED
+        );
+    }
+
+    #[test]
+    fn get_assertion_with_present_user_leaves_user_verified_flag_clear() {
+        assert_eq!(assertion_flags(&test_u2f()), FLAG_USER_PRESENT);
+    }
+}
@@ -0,0 +1,241 @@
+extern crate futures;
+extern crate u2f_core;
+
+use std::io;
+
+use futures::future;
+use futures::Future;
+use u2f_core::{
+    self_signed_attestation, verify_signature, AppId, ApprovalError, Challenge, InMemoryStore,
+    SecureCryptoOperations, UserPresence, U2F,
+};
+
+struct AlwaysPresent;
+
+impl UserPresence for AlwaysPresent {
+    fn approve_registration(
+        &self,
+        _: &AppId,
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        Box::new(future::ok(true))
+    }
+
+    fn approve_authentication(
+        &self,
+        _: &AppId,
+    ) -> Box<dyn Future<Item = bool, Error = ApprovalError>> {
+        Box::new(future::ok(true))
+    }
+
+    fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
+        Box::new(future::ok(()))
+    }
+}
+
+#[test]
+fn register_then_authenticate() {
+    let u2f = U2F::new(
+        Box::new(AlwaysPresent),
+        Box::new(SecureCryptoOperations::new(self_signed_attestation())),
+        Box::new(InMemoryStore::new()),
+        None,
+    )
+    .unwrap();
+    let application = AppId::from_bytes(&[1u8; 32]);
+
+    let registration = u2f
+        .register(application, Challenge::from_bytes(&[2u8; 32]))
+        .wait()
+        .unwrap();
+    let authentication = u2f
+        .authenticate(
+            application,
+            Challenge::from_bytes(&[3u8; 32]),
+            registration.key_handle().clone(),
+        )
+        .wait()
+        .unwrap();
+
+    assert_eq!(registration.user_public_key().len(), 65);
+    assert_eq!(authentication.counter(), 1);
+    assert!(authentication.user_present());
+
+    // application || user presence || counter || challenge
+    let mut signed_data = vec![1u8; 32];
+    signed_data.extend_from_slice(&[0x01, 0, 0, 0, 1]);
+    signed_data.extend_from_slice(&[3u8; 32]);
+    assert!(verify_signature(
+        registration.user_public_key(),
+        &signed_data,
+        authentication.signature().as_ref(),
+    )
+    .unwrap());
+}
+
+fn der_len(der: &[u8]) -> usize {
+    match der[1] {
+        0x81 => 3 + der[2] as usize,
+        0x82 => 4 + ((der[2] as usize) << 8 | der[3] as usize),
+        len => 2 + len as usize,
+    }
+}
+
+#[test]
+fn register_then_authenticate_through_apdus() {
+    let u2f = U2F::new(
+        Box::new(AlwaysPresent),
+        Box::new(SecureCryptoOperations::new(self_signed_attestation())),
+        Box::new(InMemoryStore::new()),
+        None,
+    )
+    .unwrap();
+
+    let version = u2f.process_apdu(&[0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(version, b"U2F_V2\x90\x00");
+
+    // Extended length encoding: CLA INS P1 P2 0 Lc(2) data Le(2)
+    let mut register = vec![0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 64];
+    register.extend_from_slice(&[2u8; 32]);
+    register.extend_from_slice(&[1u8; 32]);
+    register.extend_from_slice(&[0x00, 0x00]);
+    let registration = u2f.process_apdu(&register);
+
+    assert_eq!(registration[0], 0x05);
+    assert_eq!(registration[registration.len() - 2..], [0x90, 0x00]);
+    let user_public_key = &registration[1..66];
+    let key_handle_len = registration[66] as usize;
+    let key_handle = &registration[67..67 + key_handle_len];
+    let certificate = &registration[67 + key_handle_len..];
+    assert_eq!(certificate[0], 0x30);
+    let signature = &certificate[der_len(certificate)..registration.len() - 69 - key_handle_len];
+    assert_eq!(signature[0], 0x30);
+
+    let mut authenticate_data = vec![3u8; 32];
+    authenticate_data.extend_from_slice(&[1u8; 32]);
+    authenticate_data.push(key_handle_len as u8);
+    authenticate_data.extend_from_slice(key_handle);
+    let mut authenticate = vec![0x00, 0x02, 0x03, 0x00, 0x00];
+    authenticate.push((authenticate_data.len() >> 8) as u8);
+    authenticate.push(authenticate_data.len() as u8);
+    authenticate.extend_from_slice(&authenticate_data);
+    authenticate.extend_from_slice(&[0x00, 0x00]);
+    let authentication = u2f.process_apdu(&authenticate);
+
+    assert_eq!(authentication[..5], [0x01, 0, 0, 0, 1]);
+    assert_eq!(authentication[authentication.len() - 2..], [0x90, 0x00]);
+    let mut signed_data = vec![1u8; 32];
+    signed_data.extend_from_slice(&authentication[..5]);
+    signed_data.extend_from_slice(&[3u8; 32]);
+    assert!(verify_signature(
+        user_public_key,
+        &signed_data,
+        &authentication[5..authentication.len() - 2],
+    )
+    .unwrap());
+}
+
+#[test]
+fn malformed_apdu_returns_status_word() {
+    let u2f = U2F::new(
+        Box::new(AlwaysPresent),
+        Box::new(SecureCryptoOperations::new(self_signed_attestation())),
+        Box::new(InMemoryStore::new()),
+        None,
+    )
+    .unwrap();
+
+    assert_eq!(u2f.process_apdu(&[0x00, 0x01]), [0x67, 0x00]);
+    assert_eq!(u2f.process_apdu(&[0x00, 0x42, 0x00, 0x00]), [0x6d, 0x00]);
+}
+
+#[test]
+fn version_apdu_returns_version_string() {
+    let u2f = U2F::new(
+        Box::new(AlwaysPresent),
+        Box::new(SecureCryptoOperations::new(self_signed_attestation())),
+        Box::new(InMemoryStore::new()),
+        None,
+    )
+    .unwrap();
+
+    assert_eq!(
+        u2f.process_apdu(&[0x00, 0x03, 0x00, 0x00]),
+        [b'U', b'2', b'F', b'_', b'V', b'2', 0x90, 0x00]
+    );
+    assert_eq!(u2f.process_apdu(&[0x00, 0x03, 0x01, 0x00]), [0x6a, 0x86]);
+    assert_eq!(u2f.process_apdu(&[0x00, 0x03, 0x00, 0x01]), [0x6a, 0x86]);
+}
+
+#[test]
+fn long_registration_is_sent_in_full_only_with_extended_length() {
+    let u2f = U2F::new(
+        Box::new(AlwaysPresent),
+        Box::new(SecureCryptoOperations::new(self_signed_attestation())),
+        Box::new(InMemoryStore::new()),
+        None,
+    )
+    .unwrap();
+    let mut request_data = vec![2u8; 32];
+    request_data.extend_from_slice(&[1u8; 32]);
+
+    let mut extended = vec![0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 64];
+    extended.extend_from_slice(&request_data);
+    extended.extend_from_slice(&[0x00, 0x00]);
+    let registration = u2f.process_apdu(&extended);
+
+    // Version, public key, key handle, certificate and signature, not cut short
+    assert!(registration.len() > 256 + 2);
+    let key_handle_len = registration[66] as usize;
+    let certificate = &registration[67 + key_handle_len..];
+    let signature = &certificate[der_len(certificate)..certificate.len() - 2];
+    assert_eq!(signature.len(), der_len(signature));
+    assert_eq!(registration[registration.len() - 2..], [0x90, 0x00]);
+
+    let mut short = vec![0x00, 0x01, 0x00, 0x00, 64];
+    short.extend_from_slice(&request_data);
+    short.push(0x00);
+
+    assert_eq!(u2f.process_apdu(&short), [0x67, 0x00]);
+}
+
+#[test]
+fn long_registration_is_collected_with_get_response() {
+    let u2f = U2F::builder(
+        Box::new(AlwaysPresent),
+        Box::new(SecureCryptoOperations::new(self_signed_attestation())),
+        Box::new(InMemoryStore::new()),
+        None,
+    )
+    .with_response_chaining()
+    .build()
+    .unwrap();
+
+    let mut register = vec![0x00, 0x01, 0x00, 0x00, 64];
+    register.extend_from_slice(&[2u8; 32]);
+    register.extend_from_slice(&[1u8; 32]);
+    register.push(0x00);
+    let mut piece = u2f.process_apdu_on_channel(7, &register);
+    let mut registration = Vec::new();
+    let mut get_responses = 0;
+    while piece[piece.len() - 2] == 0x61 {
+        assert_eq!(piece.len(), 256 + 2);
+        registration.extend_from_slice(&piece[..piece.len() - 2]);
+        let get_response = [0x00, 0xC0, 0x00, 0x00, piece[piece.len() - 1]];
+        piece = u2f.process_apdu_on_channel(7, &get_response);
+        get_responses += 1;
+    }
+    registration.extend_from_slice(&piece);
+
+    assert_eq!(get_responses, 2);
+    assert_eq!(registration[0], 0x05);
+    assert_eq!(registration[registration.len() - 2..], [0x90, 0x00]);
+    let key_handle_len = registration[66] as usize;
+    let certificate = &registration[67 + key_handle_len..];
+    let signature = &certificate[der_len(certificate)..certificate.len() - 2];
+    assert_eq!(signature.len(), der_len(signature));
+    // Nothing is left to collect
+    assert_eq!(
+        u2f.process_apdu_on_channel(7, &[0x00, 0xC0, 0x00, 0x00, 0x00]),
+        [0x69, 0x86]
+    );
+}
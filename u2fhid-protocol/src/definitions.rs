@@ -17,6 +17,8 @@ pub const U2FHID_PROTOCOL_VERSION: u8 = 2;
 const HID_REPORT_LEN: usize = 64;
 const INITIAL_PACKET_DATA_LEN: usize = HID_REPORT_LEN - 7;
 const CONTINUATION_PACKET_DATA_LEN: usize = HID_REPORT_LEN - 5;
+/// An initialization packet followed by continuation packets 0 through 0x7f
+pub const MAX_PAYLOAD_LEN: usize = INITIAL_PACKET_DATA_LEN + 128 * CONTINUATION_PACKET_DATA_LEN;
 
 const FRAME_TYPE_INIT: u8 = 0b1000_0000;
 const FRAME_TYPE_CONT: u8 = 0b0000_0000;
@@ -45,7 +47,7 @@ pub fn transaction_timeout_duration() -> Duration {
     Duration::from_millis(3000)
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct ChannelId(pub u32);
 
 impl ChannelId {
@@ -180,7 +182,9 @@ impl Packet {
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Result<Packet, ()> {
-        assert_eq!(bytes.len(), HID_REPORT_LEN + 1);
+        if bytes.len() != HID_REPORT_LEN + 1 {
+            return Err(());
+        }
         let mut reader = Cursor::new(bytes);
         reader.read_u8().unwrap(); // TODO why do we have this extra byte to skip here
         let channel_id = ChannelId(reader.read_u32::<BigEndian>().unwrap());
@@ -6,6 +6,7 @@ extern crate futures;
 extern crate itertools;
 #[macro_use]
 extern crate quick_error;
+extern crate rand;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
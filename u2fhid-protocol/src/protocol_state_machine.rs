@@ -1,3 +1,4 @@
+use std::collections::{HashSet, VecDeque};
 use std::io;
 use std::mem;
 use std::time::Duration;
@@ -5,6 +6,7 @@ use std::time::Duration;
 use crate::definitions::*;
 use futures::{Async, Future};
 use futures::future;
+use rand::{self, Rng};
 use slog::Logger;
 use tokio_core::reactor::Handle;
 use tokio_core::reactor::Timeout;
@@ -49,34 +51,48 @@ impl State {
 
 const MAX_CHANNEL_ID: ChannelId = ChannelId(BROADCAST_CHANNEL_ID.0 - 1);
 const MIN_CHANNEL_ID: ChannelId = ChannelId(1);
+/// Once this many channels are allocated, allocating another one frees
+/// the oldest, so clients looping on INIT can't grow the set forever.
+const MAX_ALLOCATED_CHANNELS: usize = 16;
 
 #[derive(Debug)]
 struct Channels {
-    next_allocation: ChannelId,
+    allocated: HashSet<ChannelId>,
+    /// Allocated channels, oldest first
+    allocation_order: VecDeque<ChannelId>,
 }
 
 impl Channels {
     fn new() -> Channels {
         Channels {
-            next_allocation: MIN_CHANNEL_ID,
+            allocated: HashSet::new(),
+            allocation_order: VecDeque::new(),
         }
     }
 
-    fn allocate(&mut self) -> Result<ChannelId, ()> {
-        if self.next_allocation > MAX_CHANNEL_ID {
-            Err(())
-        } else {
-            let allocation = self.next_allocation;
-            self.next_allocation = self.next_allocation.checked_add(1).unwrap();
-            Ok(allocation)
+    /// Channel identifiers are picked at random so they can't be guessed
+    /// by other applications talking to the device.
+    fn allocate(&mut self) -> ChannelId {
+        if self.allocation_order.len() >= MAX_ALLOCATED_CHANNELS {
+            if let Some(oldest) = self.allocation_order.pop_front() {
+                self.allocated.remove(&oldest);
+            }
+        }
+        let mut rng = rand::thread_rng();
+        loop {
+            let channel_id = ChannelId(rng.gen());
+            if channel_id >= MIN_CHANNEL_ID
+                && channel_id <= MAX_CHANNEL_ID
+                && self.allocated.insert(channel_id)
+            {
+                self.allocation_order.push_back(channel_id);
+                return channel_id;
+            }
         }
     }
 
     fn is_valid(&self, channel_id: ChannelId) -> bool {
-        let is_broadcast = channel_id == BROADCAST_CHANNEL_ID;
-        let is_in_allocated_range =
-            channel_id >= MIN_CHANNEL_ID && channel_id < self.next_allocation;
-        is_broadcast || is_in_allocated_range
+        channel_id == BROADCAST_CHANNEL_ID || self.allocated.contains(&channel_id)
     }
 }
 
@@ -225,7 +241,15 @@ where
 
     fn check_channel_id(&self, packet: &Packet) -> Result<Option<Response>, io::Error> {
         let channel_id = packet.channel_id();
-        if !self.channels.is_valid(channel_id) {
+        // The broadcast channel may only be used to allocate a channel
+        let is_init = matches!(
+            packet,
+            Packet::Initialization {
+                command: Command::Init,
+                ..
+            }
+        );
+        if !self.channels.is_valid(channel_id) || (channel_id == BROADCAST_CHANNEL_ID && !is_init) {
             debug!(self.logger, "Invalid channel"; "id" => channel_id);
             Ok(Some(Self::error_output(
                 ErrorCode::InvalidChannel,
@@ -255,6 +279,23 @@ where
 
     fn step_with_packet(&mut self, packet: Packet) -> Result<Option<Response>, io::Error> {
         let transition = match (self.state.take(), packet) {
+            (
+                state @ State::Idle,
+                Packet::Initialization {
+                    channel_id,
+                    payload_len,
+                    ..
+                },
+            ) if payload_len > MAX_PAYLOAD_LEN => {
+                debug!(self.logger, "Payload too long"; "channel_id" => &channel_id, "payload_len" => payload_len);
+                StateTransition {
+                    new_state: state,
+                    output: Some(Self::error_output(
+                        ErrorCode::InvalidMessageLength,
+                        channel_id,
+                    )),
+                }
+            }
             (
                 State::Idle,
                 Packet::Initialization {
@@ -384,6 +425,18 @@ where
                                 }),
                             }
                         },
+                        Err(error @ RequestMessageDecodeError::PayloadLength(..)) => {
+                            debug!(self.logger, "Unexpected payload length"; "error" => error);
+                            StateTransition {
+                                new_state: State::Idle,
+                                output: Some(Response {
+                                    channel_id: receive.channel_id,
+                                    message: ResponseMessage::Error {
+                                        code: ErrorCode::InvalidMessageLength,
+                                    },
+                                }),
+                            }
+                        },
                         Err(error) => {
                             debug!(self.logger, "Unable to decode request message"; "error" => error);
                             StateTransition {
@@ -469,17 +522,22 @@ where
         let channel_id = request.channel_id;
         match request.message {
             RequestMessage::EncapsulatedRequest { data } => {
-                // TODO no unwrap
                 debug!(self.logger, "RequestMessage::EncapsulatedRequest"; "data.len" => data.len());
-                let request = u2f_core::Request::decode(&data).unwrap();
-                Ok(self.dispatch(request))
+                match u2f_core::Request::decode(&data) {
+                    Ok(request) => Ok(self.dispatch(request)),
+                    Err(err) => {
+                        info!(self.logger, "Invalid request"; "error" => %err);
+                        let mut data = Vec::new();
+                        err.status_code().write(&mut data);
+                        Ok(Box::new(future::ok(
+                            ResponseMessage::EncapsulatedResponse { data },
+                        )))
+                    }
+                }
             }
             RequestMessage::Init { nonce } => {
                 // TODO Check what channnel message came in on
-                // TODO check unwrap
-                let new_channel_id = self.channels
-                    .allocate()
-                    .expect("Failed to allocate new channel");
+                let new_channel_id = self.channels.allocate();
                 debug!(self.logger, "RequestMessage::Init"; "new_channel_id" => new_channel_id);
                 Ok(Box::new(future::ok(ResponseMessage::Init {
                     nonce,
@@ -495,7 +553,15 @@ where
                 debug!(self.logger, "RequestMessage::Ping"; "data.len" => data.len());
                 Ok(Box::new(future::ok(ResponseMessage::Pong { data: data })))
             }
-            RequestMessage::Wink => Ok(self.dispatch(u2f_core::Request::Wink)),
+            RequestMessage::Wink => {
+                debug!(self.logger, "RequestMessage::Wink");
+                // Answered with an empty WINK frame rather than an encapsulated U2F response
+                Ok(Box::new(
+                    self.service
+                        .call(u2f_core::Request::Wink)
+                        .map(|_| ResponseMessage::Wink),
+                ))
+            }
             RequestMessage::Lock { lock_time } => {
                 debug!(self.logger, "RequestMessage::Lock"; "lock_time" => lock_time.as_secs());
                 if lock_time == Duration::from_secs(0) {
@@ -544,6 +610,22 @@ mod tests {
         }
     }
 
+    struct WinkingU2FService;
+
+    impl Service for WinkingU2FService {
+        type Request = u2f_core::Request;
+        type Response = u2f_core::Response;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+        fn call(&self, req: Self::Request) -> Self::Future {
+            match req {
+                u2f_core::Request::Wink => Box::new(future::ok(u2f_core::Response::DidWink)),
+                _ => panic!("Fake service, only winks"),
+            }
+        }
+    }
+
     #[test]
     fn channels_broadcast_channel_is_valid() {
         let channels = Channels::new();
@@ -553,10 +635,73 @@ mod tests {
     #[test]
     fn channels_allocated_channel_is_valid() {
         let mut channels = Channels::new();
-        let channel_id = channels.allocate().unwrap();
+        let channel_id = channels.allocate();
         assert!(channels.is_valid(channel_id));
     }
 
+    #[test]
+    fn channels_allocating_past_limit_frees_oldest_channel() {
+        let mut channels = Channels::new();
+        let first = channels.allocate();
+        let second = channels.allocate();
+        for _ in 2..MAX_ALLOCATED_CHANNELS {
+            channels.allocate();
+        }
+        assert!(channels.is_valid(first));
+
+        let newest = channels.allocate();
+
+        assert!(!channels.is_valid(first));
+        assert!(channels.is_valid(second));
+        assert!(channels.is_valid(newest));
+        assert_eq!(channels.allocated.len(), MAX_ALLOCATED_CHANNELS);
+    }
+
+    #[test]
+    fn channels_unallocated_channel_is_invalid() {
+        let channels = Channels::new();
+        assert!(!channels.is_valid(MIN_CHANNEL_ID));
+    }
+
+    #[test]
+    fn init_allocates_distinct_channels() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger);
+
+        let first = init_channel(&mut state_machine);
+        let second = init_channel(&mut state_machine);
+
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn ping_on_broadcast_channel_is_rejected() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger);
+
+        let res = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: BROADCAST_CHANNEL_ID,
+                command: Command::Ping,
+                data: vec![1, 2, 3],
+                payload_len: 3,
+            })
+            .unwrap();
+
+        match res {
+            Some(Response {
+                message:
+                    ResponseMessage::Error {
+                        code: ErrorCode::InvalidChannel,
+                    },
+                ..
+            }) => {}
+            _ => panic!(),
+        };
+    }
+
     #[test]
     fn init() {
         let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
@@ -642,4 +787,179 @@ mod tests {
             _ => panic!(),
         };
     }
+
+    #[test]
+    fn ping_spanning_multiple_packets() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger);
+        let ping_data: Vec<u8> = (0..200u8).collect();
+
+        let channel_id = init_channel(&mut state_machine);
+        let packets = Response {
+            channel_id,
+            message: ResponseMessage::Pong {
+                data: ping_data.clone(),
+            },
+        }
+        .into_packets();
+        assert_eq!(packets.len(), 4);
+
+        let mut res = None;
+        for packet in packets {
+            let mut report = vec![0u8];
+            report.extend_from_slice(&packet.into_bytes());
+            res = state_machine
+                .accept_packet(Packet::from_bytes(&report).unwrap())
+                .unwrap();
+        }
+
+        match res {
+            Some(Response {
+                channel_id: response_channel_id,
+                message:
+                    ResponseMessage::Pong {
+                        data: response_data,
+                    },
+            }) => {
+                assert_eq!(response_channel_id, channel_id);
+                assert_eq!(response_data, ping_data);
+            }
+            _ => panic!(),
+        };
+    }
+
+    #[test]
+    fn wink_returns_empty_wink_response() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let mut state_machine = StateMachine::new(WinkingU2FService, core.handle(), logger);
+
+        let channel_id = init_channel(&mut state_machine);
+
+        let res = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id,
+                command: Command::Wink,
+                data: Vec::new(),
+                payload_len: 0,
+            })
+            .unwrap();
+
+        match res {
+            Some(
+                response @ Response {
+                    message: ResponseMessage::Wink,
+                    ..
+                },
+            ) => {
+                assert_eq!(response.channel_id, channel_id);
+                let packets = response.into_packets();
+                assert_eq!(packets.len(), 1);
+                match packets[0] {
+                    Packet::Initialization {
+                        command: Command::Wink,
+                        payload_len: 0,
+                        ..
+                    } => {}
+                    _ => panic!(),
+                }
+            }
+            _ => panic!(),
+        };
+    }
+
+    fn error_packet_bytes(response: Response) -> Vec<u8> {
+        let mut packets = response.into_packets();
+        assert_eq!(packets.len(), 1);
+        packets.pop_front().unwrap().into_bytes()
+    }
+
+    #[test]
+    fn unknown_command_returns_invalid_command_error() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger);
+
+        let channel_id = init_channel(&mut state_machine);
+        let mut report = vec![0u8];
+        channel_id.write(&mut report);
+        report.extend_from_slice(&[0x80 | 0x10, 0x00, 0x00]);
+        report.resize(65, 0);
+
+        let res = state_machine
+            .accept_packet(Packet::from_bytes(&report).unwrap())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(res.channel_id, channel_id);
+        let bytes = error_packet_bytes(res);
+        assert_eq!(bytes[4..8], [0xbf, 0x00, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn over_length_message_returns_invalid_length_error() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger);
+
+        let channel_id = init_channel(&mut state_machine);
+        let res = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id,
+                command: Command::Ping,
+                data: vec![0u8; 57],
+                payload_len: MAX_PAYLOAD_LEN + 1,
+            })
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(res.channel_id, channel_id);
+        let bytes = error_packet_bytes(res);
+        assert_eq!(bytes[4..8], [0xbf, 0x00, 0x01, 0x03]);
+
+        // The channel is left idle, ready for the next transaction
+        let res = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id,
+                command: Command::Ping,
+                data: vec![1, 2, 3],
+                payload_len: 3,
+            })
+            .unwrap();
+        match res {
+            Some(Response {
+                message: ResponseMessage::Pong { data },
+                ..
+            }) => assert_eq!(data, vec![1, 2, 3]),
+            _ => panic!(),
+        };
+    }
+
+    #[test]
+    fn init_with_wrong_length_returns_invalid_length_error() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger);
+
+        let res = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: BROADCAST_CHANNEL_ID,
+                command: Command::Init,
+                data: vec![0u8; 4],
+                payload_len: 4,
+            })
+            .unwrap();
+
+        match res {
+            Some(Response {
+                message:
+                    ResponseMessage::Error {
+                        code: ErrorCode::InvalidMessageLength,
+                    },
+                ..
+            }) => {}
+            _ => panic!(),
+        };
+    }
 }